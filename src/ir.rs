@@ -1,23 +1,28 @@
 use crate::error;
+use crate::error_at;
 use crate::intern;
-use crate::print_cursor;
 use crate::syntax;
+pub use crate::syntax::CallConv;
+pub use crate::syntax::FuncAttr;
+pub use crate::syntax::Linkage;
+pub use crate::syntax::Visibility;
 use crate::String;
+use std::collections::HashMap;
 
 #[derive(Debug, Default)]
 pub struct TypeIntern {
     pub types: Vec<Type>,
+    ids: HashMap<Type, TypeId>,
 }
 
 impl TypeIntern {
     fn intern(&mut self, ty: Type) -> TypeId {
-        for (i, interned) in self.types.iter().enumerate() {
-            if ty == *interned {
-                return i;
-            }
+        if let Some(&i) = self.ids.get(&ty) {
+            return i;
         }
         let i = self.types.len();
-        self.types.push(ty);
+        self.types.push(ty.clone());
+        self.ids.insert(ty, i);
         i
     }
 
@@ -36,7 +41,10 @@ impl TypeIntern {
 
 #[derive(Debug, Default)]
 pub struct NameTable {
-    names: Vec<(String, Def)>,
+    // Shadowed definitions are kept below the current one.
+    names: HashMap<String, Vec<Def>>,
+    // Names in definition order, so scopes can be unwound.
+    defs: Vec<String>,
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -46,29 +54,28 @@ enum Def {
     Param(ParamId),
     Local(LocalId),
     Const(ConstId),
+    Global(GlobalId),
+    Intrinsic(Intrinsic),
 }
 
 impl NameTable {
     fn def(&mut self, name: String, def: Def) {
-        self.names.push((name, def));
+        self.names.entry(name).or_default().push(def);
+        self.defs.push(name);
     }
 
     fn get(&self, name: String) -> Option<Def> {
-        for &(other, def) in self.names.iter().rev() {
-            if other == name {
-                return Some(def);
-            }
-        }
-        None
+        self.names.get(&name)?.last().copied()
     }
 
     fn enter_scope(&self) -> usize {
-        self.names.len()
+        self.defs.len()
     }
 
     fn exit_scope(&mut self, scope: usize) {
-        while scope < self.names.len() {
-            self.names.pop();
+        while scope < self.defs.len() {
+            let name = self.defs.pop().unwrap();
+            self.names.get_mut(&name).unwrap().pop();
         }
     }
 }
@@ -78,6 +85,7 @@ pub struct Module2 {
     pub func_bodys: Vec<FuncBody>,
     pub types: Vec<Type>,
     pub consts: Vec<Const>,
+    pub globals: Vec<Global>,
 }
 
 #[derive(Debug)]
@@ -86,6 +94,17 @@ pub struct Const {
     pub expr: Expr,
 }
 
+#[derive(Debug)]
+pub struct Global {
+    pub name: String,
+    pub ty: TypeId,
+    // None for globals defined in another object.
+    pub init: Option<Expr>,
+    pub linkage: Linkage,
+    // None uses the module's default visibility.
+    pub visibility: Option<Visibility>,
+}
+
 pub fn build(module: &syntax::Module) -> Module2 {
     let mut b = ModuleBuilder::default();
 
@@ -93,10 +112,29 @@ pub fn build(module: &syntax::Module) -> Module2 {
     b.add_type("i16", Type::I16);
     b.add_type("i32", Type::I32);
     b.add_type("i64", Type::I64);
+    b.add_type("i128", Type::I128);
+    b.add_type("u8", Type::U8);
+    b.add_type("u16", Type::U16);
+    b.add_type("u32", Type::U32);
+    b.add_type("u64", Type::U64);
+    b.add_type("u128", Type::U128);
     b.add_type("f32", Type::F32);
     b.add_type("f64", Type::F64);
     b.add_type("bool", Type::Bool);
 
+    b.add_intrinsic("rdtsc", Intrinsic::Rdtsc);
+    b.add_intrinsic("sat_add", Intrinsic::SatAdd);
+    b.add_intrinsic("sat_sub", Intrinsic::SatSub);
+    b.add_intrinsic("checked_add", Intrinsic::CheckedAdd);
+    b.add_intrinsic("checked_sub", Intrinsic::CheckedSub);
+    b.add_intrinsic("checked_mul", Intrinsic::CheckedMul);
+    b.add_intrinsic("trunc", Intrinsic::Trunc);
+    b.add_intrinsic("memcpy", Intrinsic::Memcpy);
+    b.add_intrinsic("memset", Intrinsic::Memset);
+    b.add_intrinsic("sqrt", Intrinsic::Sqrt);
+    b.add_intrinsic("fabs", Intrinsic::Fabs);
+    b.add_intrinsic("unordered", Intrinsic::Unordered);
+
     for type_decl in &module.type_decls {
         b.add_type_decl(type_decl);
     }
@@ -106,8 +144,17 @@ pub fn build(module: &syntax::Module) -> Module2 {
         b.names.def(const_decl.name, Def::Const(i));
     }
 
-    for decl in &module.func_decls {
-        b.add_func_decl(decl);
+    for global_decl in &module.global_decls {
+        let i = b.add_global_decl(global_decl);
+        b.names.def(global_decl.name, Def::Global(i));
+    }
+
+    let mut defined = vec![false; module.func_decls.len()];
+    for func in &module.func_bodys {
+        defined[func.id] = true;
+    }
+    for (decl, &defined) in module.func_decls.iter().zip(&defined) {
+        b.add_func_decl(decl, !defined);
     }
 
     let mut bodys = vec![];
@@ -118,9 +165,17 @@ pub fn build(module: &syntax::Module) -> Module2 {
             body: FuncBody {
                 id: func.id,
                 locals: vec![],
+                labels: vec![],
                 // FIXME This block is unnecessary.
-                body: Block { stmts: vec![] },
+                body: Block {
+                    stmts: vec![],
+                    lines: vec![],
+                },
+                line: line_of(module.text, func.span.0),
             },
+            labels_defined: vec![],
+            labels_taken: vec![],
+            line: 0,
         };
         let body = b.build_body(&func.body);
         bodys.push(body);
@@ -131,6 +186,311 @@ pub fn build(module: &syntax::Module) -> Module2 {
         func_bodys: bodys,
         types: b.types.types,
         consts: b.consts,
+        globals: b.globals,
+    }
+}
+
+/// Builds a Module2 directly, for generating IR without the syntax frontend.
+/// Types are interned and funcs, globals, and locals are handed out as ids,
+/// the same way `build` does it.
+#[derive(Default)]
+pub struct Module2Builder {
+    types: TypeIntern,
+    consts: Vec<Const>,
+    globals: Vec<Global>,
+    func_decls: Vec<FuncDecl>,
+    func_bodys: Vec<FuncBody>,
+    // The body being emitted, and its open blocks, innermost last.
+    body: Option<FuncBody>,
+    blocks: Vec<Block>,
+}
+
+impl Module2Builder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_type(&mut self, ty: Type) -> TypeId {
+        self.types.intern(ty)
+    }
+
+    pub fn get_type(&self, ty: TypeId) -> &Type {
+        self.types.get(ty)
+    }
+
+    pub fn add_struct(&mut self, name: &str, fields: &[(&str, TypeId)]) -> TypeId {
+        let fields = fields
+            .iter()
+            .map(|&(name, ty)| (intern(name), ty, None))
+            .collect();
+        self.add_type(Type::Struct(StructType {
+            name: intern(name),
+            fields: fields,
+            packed: false,
+        }))
+    }
+
+    pub fn add_const(&mut self, name: &str, expr: Expr) -> ConstId {
+        let c = Const {
+            name: intern(name),
+            expr: expr,
+        };
+        self.consts.push(c);
+        self.consts.len() - 1
+    }
+
+    /// Globals without an init are defined in another object.
+    pub fn add_global(&mut self, name: &str, ty: TypeId, init: Option<Expr>) -> GlobalId {
+        if let Some(init) = &init {
+            self.expect_type(ty, init.ty);
+        }
+        let g = Global {
+            name: intern(name),
+            ty: ty,
+            init: init,
+            linkage: Linkage::External,
+            visibility: None,
+        };
+        self.globals.push(g);
+        self.globals.len() - 1
+    }
+
+    /// The func is external until a body is added with `begin_body`.
+    pub fn add_func(
+        &mut self,
+        name: &str,
+        params: &[(&str, TypeId)],
+        ret: TypeId,
+        var_args: bool,
+    ) -> FuncId {
+        let func_decl = FuncDecl {
+            name: intern(name),
+            params: params.iter().map(|&(name, _)| intern(name)).collect(),
+            ty: FuncType {
+                params: params.iter().map(|&(_, ty)| ty).collect(),
+                ret: ret,
+                var_args: var_args,
+                call_conv: CallConv::C,
+            },
+            linkage: Linkage::External,
+            visibility: None,
+            external: true,
+            attrs: vec![],
+        };
+        self.func_decls.push(func_decl);
+        self.func_decls.len() - 1
+    }
+
+    pub fn begin_body(&mut self, func: FuncId) {
+        if self.body.is_some() {
+            panic!("begin_body called before end_body");
+        }
+        if self.func_bodys.iter().any(|body| body.id == func) {
+            panic!("func {:?} already has a body", self.func_decls[func].name);
+        }
+        self.func_decls[func].external = false;
+        self.body = Some(FuncBody {
+            id: func,
+            locals: vec![],
+            labels: vec![],
+            body: Block {
+                stmts: vec![],
+                lines: vec![],
+            },
+            line: 0,
+        });
+        self.begin_block();
+    }
+
+    pub fn end_body(&mut self) {
+        let block = self.end_block();
+        if !self.blocks.is_empty() {
+            panic!("end_body called with an open block");
+        }
+        let mut body = self.body.take().unwrap();
+        body.body = block;
+        self.func_bodys.push(body);
+    }
+
+    pub fn add_local(&mut self, ty: TypeId) -> LocalId {
+        let body = self.body_mut();
+        body.locals.push(ty);
+        body.locals.len() - 1
+    }
+
+    /// Statements are emitted into the new block until `end_block`, which
+    /// returns it for use in an if or loop.
+    pub fn begin_block(&mut self) {
+        self.blocks.push(Block {
+            stmts: vec![],
+            lines: vec![],
+        });
+    }
+
+    pub fn end_block(&mut self) -> Block {
+        self.blocks
+            .pop()
+            .expect("end_block called without an open block")
+    }
+
+    pub fn emit(&mut self, stmt: Stmt) {
+        let block = self.blocks.last_mut().expect("emit called outside a body");
+        block.stmts.push(stmt);
+        block.lines.push(0);
+    }
+
+    pub fn emit_assign(&mut self, place: Expr, value: Expr) {
+        self.expect_type(place.ty, value.ty);
+        self.emit(Stmt::Assign(place, value));
+    }
+
+    pub fn emit_expr(&mut self, e: Expr) {
+        self.emit(Stmt::Expr(e));
+    }
+
+    pub fn emit_return(&mut self, value: Expr) {
+        let func = self.body_mut().id;
+        self.expect_type(self.func_decls[func].ty.ret, value.ty);
+        self.emit(Stmt::Return(value));
+    }
+
+    pub fn unit(&mut self) -> Expr {
+        let ty = self.add_type(Type::Unit);
+        Expr {
+            kind: ExprKind::Unit,
+            ty: ty,
+        }
+    }
+
+    pub fn int(&mut self, ty: TypeId, value: i128) -> Expr {
+        if self.types.get(ty).int_bits().is_none() {
+            panic!("expected integer type, got {:?}", self.types.get(ty));
+        }
+        Expr {
+            kind: ExprKind::Integer(intern(&value.to_string())),
+            ty: ty,
+        }
+    }
+
+    /// A nul-terminated *i8. Strings are kept quoted and escaped, like the
+    /// source text, and `unescape` is applied when they're emitted.
+    pub fn string(&mut self, s: &str) -> Expr {
+        let mut text = std::string::String::from("\"");
+        for &c in s.as_bytes() {
+            match c {
+                b'"' | b'\\' => {
+                    text.push('\\');
+                    text.push(c as char);
+                }
+                b' '..=b'~' => text.push(c as char),
+                _ => text.push_str(&format!("\\x{:02x}", c)),
+            }
+        }
+        text.push('"');
+        let i8_ty = self.add_type(Type::I8);
+        Expr {
+            kind: ExprKind::String(intern(&text)),
+            ty: self.add_type(Type::Pointer(i8_ty)),
+        }
+    }
+
+    /// A param of the func whose body is being emitted.
+    pub fn param(&mut self, i: ParamId) -> Expr {
+        let func = self.body_mut().id;
+        Expr {
+            kind: ExprKind::Param(i),
+            ty: self.func_decls[func].ty.params[i],
+        }
+    }
+
+    pub fn local(&mut self, i: LocalId) -> Expr {
+        Expr {
+            kind: ExprKind::Local(i),
+            ty: self.body_mut().locals[i],
+        }
+    }
+
+    pub fn global(&self, i: GlobalId) -> Expr {
+        Expr {
+            kind: ExprKind::Global(i),
+            ty: self.globals[i].ty,
+        }
+    }
+
+    pub fn func(&mut self, i: FuncId) -> Expr {
+        let ty = self.func_decls[i].ty.clone();
+        Expr {
+            kind: ExprKind::Func(i),
+            ty: self.add_type(Type::Func(ty)),
+        }
+    }
+
+    pub fn call(&mut self, func: FuncId, args: Vec<Expr>) -> Expr {
+        let ty = self.func_decls[func].ty.clone();
+        if args.len() < ty.params.len() || (args.len() > ty.params.len() && !ty.var_args) {
+            panic!("expected {} arguments, got {}", ty.params.len(), args.len());
+        }
+        for (&param, arg) in ty.params.iter().zip(&args) {
+            self.expect_type(param, arg.ty);
+        }
+        let callee = self.func(func);
+        Expr {
+            kind: ExprKind::Call(Box::new(callee), args),
+            ty: ty.ret,
+        }
+    }
+
+    /// Field `i` of a struct, union, or tuple, or of one behind a pointer.
+    pub fn field(&mut self, e: Expr, i: u32) -> Expr {
+        let ty = match self.types.auto_deref(e.ty) {
+            Type::Struct(sty) => sty.fields[i as usize].1,
+            Type::Union(uty) => uty.fields[i as usize].1,
+            Type::Tuple(tys) => tys[i as usize],
+            ty => panic!("{:?} has no fields", ty),
+        };
+        Expr {
+            kind: ExprKind::Field(Box::new(e), i),
+            ty: ty,
+        }
+    }
+
+    /// Comparisons and logic ops are bool, everything else has the operand type.
+    pub fn binary(&mut self, op: Binop, x: Expr, y: Expr) -> Expr {
+        self.expect_type(x.ty, y.ty);
+        let ty = match op {
+            Binop::Cmp(_) | Binop::UnorderedCmp(_) => self.add_type(Type::Bool),
+            Binop::LogicAnd | Binop::LogicOr => self.add_type(Type::Bool),
+            _ => x.ty,
+        };
+        Expr {
+            kind: ExprKind::Binary(op, Box::new(x), Box::new(y)),
+            ty: ty,
+        }
+    }
+
+    pub fn finish(self) -> Module2 {
+        if self.body.is_some() {
+            panic!("finish called before end_body");
+        }
+        Module2 {
+            func_decls: self.func_decls,
+            func_bodys: self.func_bodys,
+            types: self.types.types,
+            consts: self.consts,
+            globals: self.globals,
+        }
+    }
+
+    fn body_mut(&mut self) -> &mut FuncBody {
+        self.body.as_mut().expect("no body is being emitted")
+    }
+
+    fn expect_type(&self, expected: TypeId, got: TypeId) {
+        if expected != got {
+            let expected = self.types.get(expected);
+            let got = self.types.get(got);
+            panic!("expected {:?}, got {:?}", expected, got);
+        }
     }
 }
 
@@ -138,6 +498,14 @@ struct FuncBuilder<'a> {
     text: &'a str,
     module: &'a mut ModuleBuilder,
     body: FuncBody,
+    labels_defined: Vec<bool>,
+    labels_taken: Vec<bool>,
+    // Line of the statement being built.
+    line: u32,
+}
+
+fn line_of(text: &str, offset: u16) -> u32 {
+    text[..offset as usize].matches('\n').count() as u32 + 1
 }
 
 impl<'a> FuncBuilder<'a> {
@@ -150,38 +518,71 @@ impl<'a> FuncBuilder<'a> {
         self.body.body = self.build_block(block);
         self.module.names.exit_scope(scope);
 
+        for (i, &defined) in self.labels_defined.iter().enumerate() {
+            if !defined {
+                error(format!("label {:?} is never defined", self.body.labels[i]));
+            }
+        }
+        // An indirect branch can go to any label whose address is taken.
+        let mut dests = vec![];
+        for (i, &taken) in self.labels_taken.iter().enumerate() {
+            if taken {
+                dests.push(i);
+            }
+        }
+        set_indirect_br_dests(&mut self.body.body, &dests);
+
         self.body
     }
 
+    fn label(&mut self, name: String) -> LabelId {
+        if let Some(i) = self.body.labels.iter().position(|&l| l == name) {
+            return i;
+        }
+        self.body.labels.push(name);
+        self.labels_defined.push(false);
+        self.labels_taken.push(false);
+        self.body.labels.len() - 1
+    }
+
     fn build_block(&mut self, block: &syntax::Block) -> Block {
         let scope = self.module.names.enter_scope();
-        let mut block2 = Block { stmts: vec![] };
-        for stmt in &block.stmts {
+        let mut block2 = Block {
+            stmts: vec![],
+            lines: vec![],
+        };
+        for (stmt, span) in block.stmts.iter().zip(&block.spans) {
+            let line = line_of(self.text, span.0);
+            self.line = line;
             let stmts = self.build_stmt(stmt);
             for stmt in stmts {
                 block2.stmts.push(stmt);
+                block2.lines.push(line);
             }
         }
         self.module.names.exit_scope(scope);
         block2
     }
 
-    fn build_match_expr(&mut self, pat: &syntax::Pattern, rhs: Expr) -> Option<Expr> {
-        let cond = match pat {
-            syntax::Pattern::Name(_) => return None,
-            syntax::Pattern::Tuple(elems) => unimplemented!(),
-            &syntax::Pattern::EnumVariant(name, ref elems) => {
-                let variant_index = match self.module.types.get(rhs.ty) {
-                    Type::Enum(ety) => ety.variant(name).unwrap().0,
-                    _ => panic!(),
-                };
+    fn build_match_expr(&mut self, pat: &syntax::Pattern, rhs: Expr) -> Expr {
+        let (start, end) = (pat.span.0 as usize, pat.span.1 as usize);
+        match pat.kind {
+            syntax::PatternKind::Name(_) | syntax::PatternKind::Tuple(_) => {
+                error_at(start, end, "if let needs an enum variant pattern")
+            }
+            syntax::PatternKind::EnumVariant(name, _) => {
+                let ety = self.pattern_enum(pat, rhs.ty);
+                let tag = self.pattern_variant(pat, &ety, name).1.tag;
+                let tag_type = ety.tag_type();
+                let tag_type = self.module.types.intern(tag_type);
+                // As a tag, the variant is its discriminant.
                 let pattern_tag = Expr {
-                    kind: ExprKind::EnumVariant(variant_index as u32),
-                    ty: self.module.types.intern(Type::I8),
+                    kind: ExprKind::EnumVariant(tag),
+                    ty: tag_type,
                 };
                 let tag = Expr {
                     kind: ExprKind::EnumTag(rhs.into()),
-                    ty: self.module.types.intern(Type::I8),
+                    ty: tag_type,
                 };
                 Expr {
                     kind: ExprKind::Binary(
@@ -192,13 +593,42 @@ impl<'a> FuncBuilder<'a> {
                     ty: self.module.types.intern(Type::Bool),
                 }
             }
-        };
-        Some(cond)
+        }
+    }
+
+    fn pattern_enum(&self, pat: &syntax::Pattern, ty: TypeId) -> EnumType {
+        match self.module.types.get(ty) {
+            Type::Enum(ety) => ety.clone(),
+            ty => {
+                let (start, end) = (pat.span.0 as usize, pat.span.1 as usize);
+                error_at(
+                    start,
+                    end,
+                    format!("variant pattern doesn't match {:?}", ty),
+                );
+            }
+        }
+    }
+
+    fn pattern_variant<'b>(
+        &self,
+        pat: &syntax::Pattern,
+        ety: &'b EnumType,
+        name: String,
+    ) -> (u32, &'b EnumVariant) {
+        match ety.variant(name) {
+            Some(variant) => variant,
+            None => {
+                let (start, end) = (pat.span.0 as usize, pat.span.1 as usize);
+                let message = format!("variant {:?} not found on enum {:?}", name, ety.name);
+                error_at(start, end, message);
+            }
+        }
     }
 
     fn build_stmt(&mut self, stmt: &syntax::Stmt) -> Vec<Stmt> {
         let stmt = match stmt {
-            syntax::Stmt::IfLet(pat, expr, body) => {
+            syntax::Stmt::IfLet(pat, expr, body, else_body) => {
                 let expr = self.build_expr(expr, None);
                 let scope = self.module.names.enter_scope();
                 let tmp_id = self.new_local(expr.ty);
@@ -208,20 +638,86 @@ impl<'a> FuncBuilder<'a> {
                 };
                 let tmp_init = Stmt::Assign(tmp.clone(), expr.clone().into());
                 let mut ret = vec![tmp_init];
-                let cond = self.build_match_expr(pat, tmp.clone()).unwrap();
+                let cond = self.build_match_expr(pat, tmp.clone());
                 let mut stmts = self.build_pattern(pat, expr.ty, Some(tmp));
+                let mut lines = vec![self.line; stmts.len()];
                 let body = self.build_block(body);
-                for stmt in body.stmts {
-                    stmts.push(stmt);
-                }
-                let body = Block { stmts };
+                stmts.extend(body.stmts);
+                lines.extend(body.lines);
+                let body = Block { stmts, lines };
                 self.module.names.exit_scope(scope);
-                ret.push(Stmt::If(cond, body));
+                let else_body = else_body.as_ref().map(|b| self.build_block(b));
+                ret.push(Stmt::If(cond, body, else_body));
                 return ret;
             }
-            syntax::Stmt::Break => Stmt::Break,
-            syntax::Stmt::Continue => Stmt::Continue,
-            syntax::Stmt::For(init, cond, post, body) => {
+            syntax::Stmt::Match(e, arms) => {
+                let expr = self.build_expr(e, None);
+                let ety = match self.module.types.get(expr.ty) {
+                    Type::Enum(ety) => ety.clone(),
+                    ty => {
+                        let start = e.span.0 as usize;
+                        let end = e.span.1 as usize;
+                        error_at(start, end, format!("cannot match on {:?}", ty));
+                    }
+                };
+                let tmp_id = self.new_local(expr.ty);
+                let tmp = Expr {
+                    kind: ExprKind::Local(tmp_id),
+                    ty: expr.ty,
+                };
+                let tmp_init = Stmt::Assign(tmp.clone(), expr.clone().into());
+                let line = self.line;
+                let mut xarms: Vec<(u32, Block)> = vec![];
+                let mut default = None;
+                for (pat, body) in arms {
+                    let scope = self.module.names.enter_scope();
+                    let mut stmts = self.build_pattern(pat, expr.ty, Some(tmp.clone()));
+                    let mut lines = vec![line; stmts.len()];
+                    let body = self.build_block(body);
+                    stmts.extend(body.stmts);
+                    lines.extend(body.lines);
+                    let body = Block { stmts, lines };
+                    self.module.names.exit_scope(scope);
+                    let (start, end) = (pat.span.0 as usize, pat.span.1 as usize);
+                    match pat.kind {
+                        syntax::PatternKind::EnumVariant(name, _) => {
+                            let (i, _) = self.pattern_variant(pat, &ety, name);
+                            if xarms.iter().any(|&(j, _)| i == j) {
+                                let message = format!("variant {:?} is matched twice", name);
+                                error_at(start, end, message);
+                            }
+                            xarms.push((i, body));
+                        }
+                        syntax::PatternKind::Name(_) => {
+                            if default.is_some() {
+                                error_at(start, end, "match has more than one default arm");
+                            }
+                            default = Some(body);
+                        }
+                        syntax::PatternKind::Tuple(_) => {
+                            error_at(start, end, "tuple pattern doesn't have tuple type");
+                        }
+                    }
+                }
+                return vec![tmp_init, Stmt::Match(tmp, xarms, default)];
+            }
+            &syntax::Stmt::Break(label) => Stmt::Break(label),
+            &syntax::Stmt::Continue(label) => Stmt::Continue(label),
+            &syntax::Stmt::Label(name) => {
+                let i = self.label(name);
+                if self.labels_defined[i] {
+                    error(format!("label {:?} is defined twice", name));
+                }
+                self.labels_defined[i] = true;
+                Stmt::Label(i)
+            }
+            syntax::Stmt::Goto(e) => {
+                let i8 = self.module.types.intern(Type::I8);
+                let ptr_i8 = self.module.types.intern(Type::Pointer(i8));
+                let e = self.build_expr(e, Some(ptr_i8));
+                Stmt::IndirectBr(e, vec![])
+            }
+            &syntax::Stmt::For(label, ref init, ref cond, ref post, ref body) => {
                 let scope = self.module.names.enter_scope();
                 let init = self.build_stmt(init);
                 let bool = self.module.types.intern(Type::Bool);
@@ -229,7 +725,7 @@ impl<'a> FuncBuilder<'a> {
                 let post = self.build_stmt(post);
                 let body = self.build_block(body);
                 self.module.names.exit_scope(scope);
-                Stmt::For(init.into(), cond, post.into(), body)
+                Stmt::For(label, init.into(), cond, post.into(), body)
             }
             syntax::Stmt::OpAssign(op, x, y) => {
                 let x2 = Box::new((*x).clone());
@@ -247,17 +743,18 @@ impl<'a> FuncBuilder<'a> {
                 let y = self.build_expr(y, Some(x.ty));
                 Stmt::Assign(x, y)
             }
-            syntax::Stmt::While(cond, body) => {
+            &syntax::Stmt::While(label, ref cond, ref body) => {
                 let bool = self.module.types.intern(Type::Bool);
                 let cond = self.build_expr(cond, Some(bool));
                 let body = self.build_block(body);
-                Stmt::While(cond, body)
+                Stmt::While(label, cond, body)
             }
-            syntax::Stmt::If(cond, body) => {
+            syntax::Stmt::If(cond, body, else_body) => {
                 let bool = self.module.types.intern(Type::Bool);
                 let cond = self.build_expr(cond, Some(bool));
                 let body = self.build_block(body);
-                Stmt::If(cond, body)
+                let else_body = else_body.as_ref().map(|b| self.build_block(b));
+                Stmt::If(cond, body, else_body)
             }
             syntax::Stmt::Let(pattern, ty, e) => {
                 let ty = match ty {
@@ -271,7 +768,7 @@ impl<'a> FuncBuilder<'a> {
                 let ty = match (&e, ty) {
                     (_, Some(ty)) => ty,
                     (Some(e), _) => e.ty,
-                    _ => panic!("let must have type or expression"),
+                    _ => error("let must have type or expression"),
                 };
 
                 return self.build_pattern(pattern, ty, e);
@@ -291,12 +788,13 @@ impl<'a> FuncBuilder<'a> {
 
     fn build_tuple_pattern(
         &mut self,
+        pat: &syntax::Pattern,
         pats: &[syntax::Pattern],
         tys: &[TypeId],
         rhs: Expr,
     ) -> Vec<Stmt> {
         let mut assigns = vec![];
-        assert_eq!(pats.len(), tys.len());
+        self.check_pattern_len(pat, pats, tys);
         for (i, (elem, &ty)) in pats.iter().zip(tys).enumerate() {
             let i = i as u32;
             let field = Expr {
@@ -313,13 +811,14 @@ impl<'a> FuncBuilder<'a> {
 
     fn build_enum_pattern(
         &mut self,
+        pat: &syntax::Pattern,
         variant: u32,
         pats: &[syntax::Pattern],
         tys: &[TypeId],
         rhs: Expr,
     ) -> Vec<Stmt> {
         let mut assigns = vec![];
-        assert_eq!(pats.len(), tys.len());
+        self.check_pattern_len(pat, pats, tys);
         for (i, (elem, &ty)) in pats.iter().zip(tys).enumerate() {
             let i = i as u32;
             let field = Expr {
@@ -334,6 +833,14 @@ impl<'a> FuncBuilder<'a> {
         assigns
     }
 
+    fn check_pattern_len(&self, pat: &syntax::Pattern, pats: &[syntax::Pattern], tys: &[TypeId]) {
+        if pats.len() != tys.len() {
+            let (start, end) = (pat.span.0 as usize, pat.span.1 as usize);
+            let message = format!("expected {} patterns, got {}", tys.len(), pats.len());
+            error_at(start, end, message);
+        }
+    }
+
     fn build_pattern(&mut self, lhs: &syntax::Pattern, ty: TypeId, rhs: Option<Expr>) -> Vec<Stmt> {
         let local_id = self.new_local(ty);
         let local = Expr {
@@ -344,30 +851,29 @@ impl<'a> FuncBuilder<'a> {
         if let Some(e) = rhs {
             assigns.push(Stmt::Assign(local.clone(), e));
         }
-        match lhs {
-            &syntax::Pattern::EnumVariant(name, ref elems) => {
-                let (i, elem_tys) = match self.module.types.get(ty) {
-                    Type::Enum(ety) => {
-                        let (i, variant) = ety.variant(name).unwrap();
-                        (i, variant.args.clone())
-                    }
-                    _ => panic!(),
-                };
-                let stmts = self.build_enum_pattern(i, elems, &elem_tys, local);
+        match lhs.kind {
+            syntax::PatternKind::EnumVariant(name, ref elems) => {
+                let ety = self.pattern_enum(lhs, ty);
+                let (i, variant) = self.pattern_variant(lhs, &ety, name);
+                let elem_tys = variant.args.clone();
+                let stmts = self.build_enum_pattern(lhs, i, elems, &elem_tys, local);
                 for stmt in stmts {
                     assigns.push(stmt);
                 }
             }
-            &syntax::Pattern::Name(name) => {
+            syntax::PatternKind::Name(name) => {
                 self.module.names.def(name, Def::Local(local_id));
             }
-            syntax::Pattern::Tuple(elems) => {
+            syntax::PatternKind::Tuple(ref elems) => {
                 // Unwrap type as tuple, get element types.
                 let elem_tys = match self.module.types.get(ty) {
                     Type::Tuple(elem_tys) => elem_tys.clone(),
-                    _ => panic!("tuple pattern doesn't have tuple type"),
+                    _ => {
+                        let (start, end) = (lhs.span.0 as usize, lhs.span.1 as usize);
+                        error_at(start, end, "tuple pattern doesn't have tuple type");
+                    }
                 };
-                let stmts = self.build_tuple_pattern(elems, &elem_tys, local);
+                let stmts = self.build_tuple_pattern(lhs, elems, &elem_tys, local);
                 for stmt in stmts {
                     assigns.push(stmt);
                 }
@@ -388,19 +894,22 @@ impl<'a> FuncBuilder<'a> {
             if x.ty != env {
                 let start = e.span.0 as usize;
                 let end = e.span.1 as usize;
-                print_cursor(self.text, start, end);
-                println!(
-                    "expected {:?}, got {:?}",
-                    self.module.types.get(env),
-                    self.module.types.get(x.ty)
+                error_at(
+                    start,
+                    end,
+                    format!(
+                        "expected {:?}, got {:?}",
+                        self.module.types.get(env),
+                        self.module.types.get(x.ty)
+                    ),
                 );
-                error();
             }
         }
         x
     }
 
     fn infer_expr(&mut self, e: &syntax::Expr, env: Option<TypeId>) -> Expr {
+        let (start, end) = (e.span.0 as usize, e.span.1 as usize);
         let (kind, ty) = match &e.kind {
             &syntax::ExprKind::TupleField(ref tuple, i) => {
                 let tuple = self.build_expr(tuple, None);
@@ -409,9 +918,7 @@ impl<'a> FuncBuilder<'a> {
                     ty => {
                         let start = e.span.0 as usize;
                         let end = e.span.1 as usize;
-                        print_cursor(self.text, start, end);
-                        println!("expected tuple, got {:?}", ty);
-                        error();
+                        error_at(start, end, format!("expected tuple, got {:?}", ty));
                     }
                 };
                 (ExprKind::Field(tuple.into(), i), elem_ty)
@@ -443,11 +950,11 @@ impl<'a> FuncBuilder<'a> {
             syntax::ExprKind::Null => {
                 let ty = match env {
                     Some(ty) => ty,
-                    None => panic!("cannot infer type of null"),
+                    None => error_at(start, end, "cannot infer type of null"),
                 };
                 match self.module.types.get(ty) {
                     Type::Pointer(_) => {}
-                    ty => panic!("expected {:?}, got null", ty),
+                    ty => error_at(start, end, format!("expected {:?}, got null", ty)),
                 };
                 (ExprKind::Null, ty)
             }
@@ -456,6 +963,11 @@ impl<'a> FuncBuilder<'a> {
                 let i64 = self.module.types.intern(Type::I64);
                 (ExprKind::Sizeof(ty), i64)
             }
+            syntax::ExprKind::Alignof(ty) => {
+                let ty = self.module.build_type(ty);
+                let i64 = self.module.types.intern(Type::I64);
+                (ExprKind::Alignof(ty), i64)
+            }
             syntax::ExprKind::Index(p, i) => {
                 let p = self.build_expr(p, None);
                 let i = self.build_expr(i, None);
@@ -472,11 +984,19 @@ impl<'a> FuncBuilder<'a> {
                     let env = match env {
                         Some(ty) => match self.module.types.get(ty) {
                             Type::Pointer(ty) => Some(*ty),
-                            _ => panic!("address-of expression should have pointer type"),
+                            ty => error_at(start, end, format!("expected {:?}, got pointer", ty)),
                         },
                         None => None,
                     };
+                    let span = e.span;
                     let e = self.build_expr(e, env);
+                    if self.is_bit_field(&e) {
+                        error_at(
+                            span.0 as usize,
+                            span.1 as usize,
+                            "cannot take the address of a bit-field",
+                        );
+                    }
                     let ty = self.module.types.intern(Type::Pointer(e.ty));
                     (ExprKind::Unary(Unop::AddressOf, e.into()), ty)
                 }
@@ -488,20 +1008,127 @@ impl<'a> FuncBuilder<'a> {
                     let e = self.build_expr(e, env);
                     let ty = match self.module.types.get(e.ty) {
                         Type::Pointer(ty) => *ty,
-                        _ => panic!("expected pointer type in deref"),
+                        ty => error_at(start, end, format!("cannot dereference {:?}", ty)),
                     };
                     (ExprKind::Unary(Unop::Deref, e.into()), ty)
                 }
+                syntax::MINUS => {
+                    let span = e.span;
+                    let e = self.build_expr(e, env);
+                    let ty = self.module.types.get(e.ty);
+                    let ok = match ty {
+                        Type::Bool => false,
+                        ty if ty.kind() == TypeKind::Scalar => {
+                            ty.scalar_kind() != ScalarKind::Pointer
+                        }
+                        _ => false,
+                    };
+                    if !ok {
+                        error_at(
+                            span.0 as usize,
+                            span.1 as usize,
+                            format!("cannot negate {:?}", ty),
+                        );
+                    }
+                    let ty = e.ty;
+                    let kind = ExprKind::Unary(Unop::Neg, e.into());
+                    (fold(&self.module.types, &kind, ty).unwrap_or(kind), ty)
+                }
+                syntax::TILDE => {
+                    let span = e.span;
+                    let e = self.build_expr(e, env);
+                    let ty = self.module.types.get(e.ty);
+                    if ty.int_bits().is_none() {
+                        error_at(
+                            span.0 as usize,
+                            span.1 as usize,
+                            format!("expected integer, got {:?}", ty),
+                        );
+                    }
+                    let ty = e.ty;
+                    let kind = ExprKind::Unary(Unop::BitNot, e.into());
+                    (fold(&self.module.types, &kind, ty).unwrap_or(kind), ty)
+                }
+                syntax::BANG => {
+                    let span = e.span;
+                    let e = self.build_expr(e, None);
+                    let ty = self.module.types.get(e.ty);
+                    if *ty != Type::Bool && ty.int_bits().is_none() {
+                        error_at(
+                            span.0 as usize,
+                            span.1 as usize,
+                            format!("expected bool or integer, got {:?}", ty),
+                        );
+                    }
+                    let bool = self.module.types.intern(Type::Bool);
+                    let kind = ExprKind::Unary(Unop::LogicNot, e.into());
+                    (fold(&self.module.types, &kind, bool).unwrap_or(kind), bool)
+                }
                 op => unimplemented!("unary operator {:?}", op),
             },
             syntax::ExprKind::Bool(b) => {
                 let bool = self.module.types.intern(Type::Bool);
                 (ExprKind::Bool(*b), bool)
             }
-            syntax::ExprKind::Cast(e, ty) => {
-                let e = self.build_expr(e, None);
+            syntax::ExprKind::Cond(cond, x, y) => {
+                let bool = self.module.types.intern(Type::Bool);
+                let cond = self.build_expr(cond, Some(bool));
+                // null can't be inferred on its own, so type it from the other arm.
+                let (x, y) = match (&x.kind, env) {
+                    (syntax::ExprKind::Null, None) => {
+                        let y = self.build_expr(y, None);
+                        (self.build_expr(x, Some(y.ty)), y)
+                    }
+                    _ => {
+                        let x = self.build_expr(x, env);
+                        let y = self.build_expr(y, Some(x.ty));
+                        (x, y)
+                    }
+                };
+                let ty = x.ty;
+                (ExprKind::Cond(cond.into(), x.into(), y.into()), ty)
+            }
+            syntax::ExprKind::InlineAsm {
+                template,
+                constraints,
+                operands,
+                clobbers,
+            } => {
+                let mut xoperands = vec![];
+                for (operand, constraint) in operands.iter().zip(constraints) {
+                    let x = self.build_expr(operand, None);
+                    let start = operand.span.0 as usize;
+                    let end = operand.span.1 as usize;
+                    if self.module.types.get(x.ty).kind() != TypeKind::Scalar {
+                        error_at(start, end, "asm operands must be scalars");
+                    }
+                    if constraint[1..].starts_with('=') && !is_place(&self.module.types, &x) {
+                        error_at(start, end, "asm output operands must be places");
+                    }
+                    xoperands.push(x);
+                }
+                let kind = ExprKind::InlineAsm {
+                    template: *template,
+                    constraints: constraints.clone(),
+                    operands: xoperands,
+                    clobbers: clobbers.clone(),
+                };
+                (kind, self.module.types.intern(Type::Unit))
+            }
+            syntax::ExprKind::Cast(x, ty) => {
+                let x = self.build_expr(x, None);
                 let ty = self.module.build_type(ty);
-                (ExprKind::Cast(e.into(), ty), ty)
+                let from = self.module.types.get(x.ty);
+                let to = self.module.types.get(ty);
+                // Enums cast to their discriminant.
+                if let Type::Enum(_) = from {
+                    if to.int_bits().is_none() {
+                        error_at(start, end, "an enum can only be cast to an integer type");
+                    }
+                } else if !castable(from, to) {
+                    error_at(start, end, format!("cannot cast {:?} to {:?}", from, to));
+                }
+                (ExprKind::Cast(x.into(), ty), ty)
             }
             &syntax::ExprKind::Field(ref e, field_name) => {
                 let e = self.build_expr(e, None);
@@ -509,13 +1136,30 @@ impl<'a> FuncBuilder<'a> {
                     Type::Struct(sty) => {
                         let i = match sty.field_index(field_name) {
                             Some(i) => i,
-                            None => {
-                                panic!("field {:?} not found on struct {:?}", field_name, sty.name)
-                            }
+                            None => error_at(
+                                start,
+                                end,
+                                format!(
+                                    "field {:?} not found on struct {:?}",
+                                    field_name, sty.name
+                                ),
+                            ),
                         };
                         let ty = sty.fields[i].1;
                         (ExprKind::Field(e.into(), i as u32), ty)
                     }
+                    Type::Union(uty) => {
+                        let i = match uty.field_index(field_name) {
+                            Some(i) => i,
+                            None => error_at(
+                                start,
+                                end,
+                                format!("field {:?} not found on union {:?}", field_name, uty.name),
+                            ),
+                        };
+                        let ty = uty.fields[i].1;
+                        (ExprKind::Field(e.into(), i as u32), ty)
+                    }
                     Type::Enum(ety) => {
                         let mut i = None;
                         for (j, variant) in ety.variants.iter().enumerate() {
@@ -526,29 +1170,68 @@ impl<'a> FuncBuilder<'a> {
                         }
                         let i = match i {
                             Some(i) => i,
-                            None => panic!(
-                                "enum variant {:?} not found on enum type {:?}",
-                                field_name, ety.name
+                            None => error_at(
+                                start,
+                                end,
+                                format!(
+                                    "enum variant {:?} not found on enum type {:?}",
+                                    field_name, ety.name
+                                ),
                             ),
                         };
                         let i = i as u32;
                         (ExprKind::EnumVariant(i), e.ty)
                     }
-                    _ => panic!(),
+                    ty => error_at(start, end, format!("{:?} has no fields", ty)),
                 }
             }
             syntax::ExprKind::Struct(fields) => {
                 let ty = match env {
-                    None => panic!(),
+                    None => error_at(start, end, "struct literal needs a type annotation"),
                     Some(ty) => self.module.types.get(ty),
                 };
                 let sty = match ty {
                     Type::Struct(ty) => ty.clone(),
-                    _ => panic!(),
+                    // A union literal sets one field, like a C designated initializer.
+                    Type::Union(uty) => {
+                        let uty = uty.clone();
+                        if fields.len() != 1 {
+                            let start = e.span.0 as usize;
+                            let end = e.span.1 as usize;
+                            error_at(start, end, "union literal must set exactly one field");
+                        }
+                        let (name, ref x) = fields[0];
+                        let i = match uty.field_index(name) {
+                            Some(i) => i,
+                            None => error_at(
+                                start,
+                                end,
+                                format!("field {:?} not found on union {:?}", name, uty.name),
+                            ),
+                        };
+                        let x = self.build_expr(x, Some(uty.fields[i].1));
+                        let kind = ExprKind::Struct(vec![(i as u32, x)]);
+                        return Expr {
+                            kind: kind,
+                            ty: env.unwrap(),
+                        };
+                    }
+                    ty => error_at(
+                        start,
+                        end,
+                        format!("struct literal can't have type {:?}", ty),
+                    ),
                 };
                 let mut fields2 = vec![];
                 for (name, e) in fields {
-                    let field_index = sty.field_index(*name).unwrap();
+                    let field_index = match sty.field_index(*name) {
+                        Some(i) => i,
+                        None => error_at(
+                            start,
+                            end,
+                            format!("field {:?} not found on struct {:?}", name, sty.name),
+                        ),
+                    };
                     let field_type = sty.fields[field_index].1;
                     let e = self.build_expr(e, Some(field_type));
                     fields2.push((field_index as u32, e));
@@ -563,13 +1246,14 @@ impl<'a> FuncBuilder<'a> {
                     None => (None, None),
                     Some(ty) => match self.module.types.get(ty) {
                         &Type::Array(elem_ty, n) => (Some(elem_ty), Some(n)),
-                        ty => panic!("expected {:?}, got {:?}", ty, e),
+                        ty => error_at(start, end, format!("expected {:?}, got array", ty)),
                     },
                 };
                 if let Some(n) = n {
                     let n = n as usize;
                     if elems.len() != n {
-                        panic!("expected array with {:?} elems, got {:?}", n, elems.len());
+                        let msg = format!("expected array with {} elems, got {}", n, elems.len());
+                        error_at(start, end, msg);
                     }
                 }
                 let mut elems2 = vec![];
@@ -581,15 +1265,39 @@ impl<'a> FuncBuilder<'a> {
                     Some(ty) => ty,
                     None => match elems2.first() {
                         Some(e) => e.ty,
-                        None => panic!("cannot infer type of empty array"),
+                        None => error_at(start, end, "cannot infer type of empty array"),
                     },
                 };
                 let array_ty = Type::Array(elem_ty, elems2.len() as u32);
                 let array_ty = self.module.types.intern(array_ty);
                 (ExprKind::Array(elems2), array_ty)
             }
+            syntax::ExprKind::ArrayRepeat(x, n) => {
+                let n = self.module.array_len(n);
+                let elem_ty = match env.map(|ty| self.module.types.get(ty)) {
+                    None => None,
+                    Some(&Type::Array(elem_ty, m)) if m == n => Some(elem_ty),
+                    Some(ty) => {
+                        let start = e.span.0 as usize;
+                        let end = e.span.1 as usize;
+                        error_at(
+                            start,
+                            end,
+                            format!("expected {:?}, got array of {} elems", ty, n),
+                        );
+                    }
+                };
+                let x = self.build_expr(x, elem_ty);
+                let array_ty = self.module.types.intern(Type::Array(x.ty, n));
+                (ExprKind::ArrayRepeat(x.into(), n), array_ty)
+            }
             syntax::ExprKind::Unit => (ExprKind::Unit, self.module.types.intern(Type::Unit)),
             syntax::ExprKind::Call(func, args) => {
+                if let syntax::ExprKind::Name(name) = func.kind {
+                    if let Some(Def::Intrinsic(intrinsic)) = self.module.names.get(name) {
+                        return self.build_intrinsic(e, intrinsic, args, env);
+                    }
+                }
                 let func = self.build_expr(func, None);
                 if let ExprKind::EnumVariant(i) = func.kind {
                     let variant = match self.module.types.get(func.ty) {
@@ -600,14 +1308,16 @@ impl<'a> FuncBuilder<'a> {
                     if args.len() != variant.args.len() {
                         let start = e.span.0 as usize;
                         let end = e.span.1 as usize;
-                        print_cursor(self.text, start, end);
-                        println!(
-                            "enum variant {:?} has {:?} args, got {:?}",
-                            variant.name,
-                            variant.args.len(),
-                            args.len()
+                        error_at(
+                            start,
+                            end,
+                            format!(
+                                "enum variant {:?} has {:?} args, got {:?}",
+                                variant.name,
+                                variant.args.len(),
+                                args.len()
+                            ),
                         );
-                        error();
                     }
                     for (arg, &ty) in args.iter().zip(&variant.args) {
                         let arg = self.build_expr(arg, Some(ty));
@@ -634,21 +1344,19 @@ impl<'a> FuncBuilder<'a> {
                 // Verify number of call args count.
                 if fnty.var_args {
                     if args.len() < params.len() {
-                        println!(
+                        error(format!(
                             "var args function requires {} params, got {}",
                             params.len(),
                             args.len()
-                        );
-                        error();
+                        ));
                     }
                 } else {
                     if args.len() != params.len() {
-                        println!(
+                        error(format!(
                             "function has {} params, but {} args were supplied",
                             params.len(),
                             args.len()
-                        );
-                        error();
+                        ));
                     }
                 }
                 // Verify arg types match inferred function type.
@@ -665,29 +1373,53 @@ impl<'a> FuncBuilder<'a> {
 
                 (ExprKind::Call(func.into(), args), fnty.ret)
             }
+            &syntax::ExprKind::LabelAddr(name) => {
+                let i = self.label(name);
+                self.labels_taken[i] = true;
+                let i8 = self.module.types.intern(Type::I8);
+                let ptr_i8 = self.module.types.intern(Type::Pointer(i8));
+                (ExprKind::LabelAddr(i), ptr_i8)
+            }
             syntax::ExprKind::String(s) => {
                 let i8 = self.module.types.intern(Type::I8);
                 let ptr_i8 = self.module.types.intern(Type::Pointer(i8));
-                (ExprKind::String(*s), ptr_i8)
+                match env.map(|ty| self.module.types.get(ty)) {
+                    Some(&Type::Array(elem_ty, n)) if elem_ty == i8 => {
+                        let len = unescape(s).len();
+                        if len > n as usize {
+                            let start = e.span.0 as usize;
+                            let end = e.span.1 as usize;
+                            error_at(
+                                start,
+                                end,
+                                format!("string of length {} doesn't fit in [{}]i8", len, n),
+                            );
+                        }
+                        (ExprKind::String(*s), env.unwrap())
+                    }
+                    _ => (ExprKind::String(*s), ptr_i8),
+                }
             }
             syntax::ExprKind::Binary(op, x, y) => {
-                let op = match op {
-                    syntax::PLUS => Binop::Add,
-                    syntax::MINUS => Binop::Sub,
-                    syntax::STAR => Binop::Mul,
-                    syntax::SLASH => Binop::Div,
-                    syntax::LT => Binop::Cmp(Predicate::Lt),
-                    syntax::GT => Binop::Cmp(Predicate::Gt),
-                    syntax::LE => Binop::Cmp(Predicate::Le),
-                    syntax::GE => Binop::Cmp(Predicate::Ge),
-                    syntax::EQ => Binop::Cmp(Predicate::Eq),
-                    syntax::NE => Binop::Cmp(Predicate::Ne),
-                    syntax::AND => Binop::And,
-                    syntax::AMPERSAND => Binop::And,
-                    syntax::LSHIFT => Binop::Shl,
-                    syntax::RSHIFT => Binop::Shr,
-                    _ => panic!(),
-                };
+                let op = binop(*op);
+                // null can't be inferred on its own, so type it from the other operand.
+                if let (Binop::Cmp(_), syntax::ExprKind::Null) = (op, &x.kind) {
+                    let y = self.build_expr(y, None);
+                    let x = self.build_expr(x, Some(y.ty));
+                    let ty = self.module.types.intern(Type::Bool);
+                    return Expr {
+                        kind: ExprKind::Binary(op, x.into(), y.into()),
+                        ty,
+                    };
+                }
+                if let Binop::LogicAnd | Binop::LogicOr = op {
+                    let bool = self.module.types.intern(Type::Bool);
+                    let x = self.build_expr(x, Some(bool));
+                    let y = self.build_expr(y, Some(bool));
+                    let kind = ExprKind::Binary(op, x.into(), y.into());
+                    let kind = fold(&self.module.types, &kind, bool).unwrap_or(kind);
+                    return Expr { kind, ty: bool };
+                }
                 let x = self.build_expr(x, None);
                 let i8 = self.module.types.intern(Type::I8);
                 let i32 = self.module.types.intern(Type::I32);
@@ -696,16 +1428,23 @@ impl<'a> FuncBuilder<'a> {
                     (ScalarKind::Pointer, Binop::Add) => i32,
                     (ScalarKind::Pointer, Binop::Sub) => x.ty,
                     (ScalarKind::Pointer, Binop::Cmp(_)) => x.ty,
-                    (ScalarKind::Pointer, op) => panic!("pointer not allowed in {:?} expr", op),
+                    (ScalarKind::Pointer, op) => {
+                        error_at(start, end, format!("pointer not allowed in {:?} expr", op))
+                    }
                     (ScalarKind::Int, Binop::Shl) => i8,
                     _ => x.ty,
                 };
                 let y = self.build_expr(y, Some(y_ty));
-                let ty = match op {
-                    Binop::Cmp(_) => self.module.types.intern(Type::Bool),
+                let x_kind = self.module.types.get(x.ty).scalar_kind();
+                let ty = match (op, x_kind) {
+                    (Binop::Cmp(_), _) => self.module.types.intern(Type::Bool),
+                    // The difference of two pointers is a count of elements.
+                    (Binop::Sub, ScalarKind::Pointer) => self.module.types.intern(Type::I64),
                     _ => x.ty,
                 };
-                (ExprKind::Binary(op, x.into(), y.into()), ty)
+                let kind = ExprKind::Binary(op, x.into(), y.into());
+                let kind = fold(&self.module.types, &kind, ty).unwrap_or(kind);
+                (kind, ty)
             }
             syntax::ExprKind::Float(s) => {
                 let f32 = self.module.types.intern(Type::F32);
@@ -722,7 +1461,8 @@ impl<'a> FuncBuilder<'a> {
                 let ty = match env {
                     None => self.module.types.intern(Type::I32),
                     Some(ty) => match self.module.types.get(ty) {
-                        Type::I8 | Type::I16 | Type::I32 | Type::I64 => ty,
+                        Type::I8 | Type::I16 | Type::I32 | Type::I64 | Type::I128 => ty,
+                        Type::U8 | Type::U16 | Type::U32 | Type::U64 | Type::U128 => ty,
                         _ => self.module.types.intern(Type::I32),
                     },
                 };
@@ -730,8 +1470,7 @@ impl<'a> FuncBuilder<'a> {
             }
             syntax::ExprKind::Name(name) => match self.module.names.get(*name) {
                 None => {
-                    println!("undefined symbol {:?}", name);
-                    error();
+                    error(format!("undefined symbol {:?}", name));
                 }
                 Some(def) => match def {
                     Def::Func(i) => {
@@ -752,11 +1491,217 @@ impl<'a> FuncBuilder<'a> {
                         let ty = self.module.consts[i].expr.ty;
                         (ExprKind::Const(i), ty)
                     }
+                    Def::Global(i) => (ExprKind::Global(i), self.module.globals[i].ty),
+                    Def::Intrinsic(_) => {
+                        let start = e.span.0 as usize;
+                        let end = e.span.1 as usize;
+                        error_at(
+                            start,
+                            end,
+                            format!("intrinsic {:?} can only be called", name),
+                        );
+                    }
                 },
             },
         };
         Expr { kind, ty }
     }
+
+    fn is_bit_field(&self, e: &Expr) -> bool {
+        let (x, i) = match &e.kind {
+            &ExprKind::Field(ref x, i) => (x, i as usize),
+            _ => return false,
+        };
+        match self.module.types.auto_deref(x.ty) {
+            Type::Struct(sty) => sty.fields[i].2.is_some(),
+            _ => false,
+        }
+    }
+
+    fn build_intrinsic(
+        &mut self,
+        e: &syntax::Expr,
+        intrinsic: Intrinsic,
+        args: &[syntax::Expr],
+        env: Option<TypeId>,
+    ) -> Expr {
+        let nargs = match intrinsic {
+            Intrinsic::Rdtsc => 0,
+            Intrinsic::SatAdd | Intrinsic::SatSub => 2,
+            Intrinsic::CheckedAdd | Intrinsic::CheckedSub | Intrinsic::CheckedMul => 2,
+            Intrinsic::Trunc => 2,
+            Intrinsic::Memcpy | Intrinsic::Memset => 3,
+            Intrinsic::Sqrt | Intrinsic::Fabs | Intrinsic::Unordered => 1,
+        };
+        if args.len() != nargs {
+            let start = e.span.0 as usize;
+            let end = e.span.1 as usize;
+            error_at(
+                start,
+                end,
+                format!(
+                    "intrinsic {:?} has {} args, got {}",
+                    intrinsic,
+                    nargs,
+                    args.len()
+                ),
+            );
+        }
+        match intrinsic {
+            Intrinsic::Rdtsc => {
+                let i64 = self.module.types.intern(Type::I64);
+                let kind = ExprKind::Intrinsic(intrinsic, vec![]);
+                Expr { kind, ty: i64 }
+            }
+            Intrinsic::SatAdd
+            | Intrinsic::SatSub
+            | Intrinsic::CheckedAdd
+            | Intrinsic::CheckedSub
+            | Intrinsic::CheckedMul => {
+                let x = self.infer_expr(&args[0], env);
+                match self.module.types.get(x.ty) {
+                    Type::I8 | Type::I16 | Type::I32 | Type::I64 | Type::I128 => {}
+                    Type::U8 | Type::U16 | Type::U32 | Type::U64 | Type::U128 => {}
+                    ty => {
+                        let start = args[0].span.0 as usize;
+                        let end = args[0].span.1 as usize;
+                        error_at(start, end, format!("expected integer, got {:?}", ty));
+                    }
+                }
+                let y = self.build_expr(&args[1], Some(x.ty));
+                let ty = x.ty;
+                let kind = ExprKind::Intrinsic(intrinsic, vec![x, y]);
+                Expr { kind, ty }
+            }
+            Intrinsic::Trunc => {
+                let x = self.build_expr(&args[0], None);
+                let ty = match self.build_expr(&args[1], None).kind {
+                    ExprKind::Type(ty) => ty,
+                    _ => {
+                        let start = args[1].span.0 as usize;
+                        let end = args[1].span.1 as usize;
+                        error_at(start, end, "expected type");
+                    }
+                };
+                let from = self.module.types.get(x.ty);
+                let to = self.module.types.get(ty);
+                match (from.int_bits(), to.int_bits()) {
+                    (Some(from_bits), Some(to_bits)) if to_bits < from_bits => {}
+                    _ => {
+                        let start = e.span.0 as usize;
+                        let end = e.span.1 as usize;
+                        error_at(
+                            start,
+                            end,
+                            format!("cannot truncate {:?} to {:?}", from, to),
+                        );
+                    }
+                }
+                let kind = ExprKind::Intrinsic(intrinsic, vec![x]);
+                Expr { kind, ty }
+            }
+            Intrinsic::Memcpy | Intrinsic::Memset => {
+                let dst = self.build_expr(&args[0], None);
+                let x = match intrinsic {
+                    Intrinsic::Memcpy => self.build_expr(&args[1], None),
+                    _ => {
+                        let u8 = self.module.types.intern(Type::U8);
+                        let byte = self.build_expr(&args[1], Some(u8));
+                        let ty = self.module.types.get(byte.ty);
+                        if ty.int_bits() != Some(8) {
+                            let start = args[1].span.0 as usize;
+                            let end = args[1].span.1 as usize;
+                            error_at(start, end, format!("expected i8 or u8, got {:?}", ty));
+                        }
+                        byte
+                    }
+                };
+                let ptrs = match intrinsic {
+                    Intrinsic::Memcpy => &args[..2],
+                    _ => &args[..1],
+                };
+                for (arg, x) in ptrs.iter().zip(&[&dst, &x]) {
+                    let ty = self.module.types.get(x.ty);
+                    if let Type::Pointer(_) = ty {
+                        continue;
+                    }
+                    let start = arg.span.0 as usize;
+                    let end = arg.span.1 as usize;
+                    error_at(start, end, format!("expected pointer, got {:?}", ty));
+                }
+                let i64 = self.module.types.intern(Type::I64);
+                let n = self.build_expr(&args[2], Some(i64));
+                let ty = self.module.types.get(n.ty);
+                if ty.int_bits().is_none() {
+                    let start = args[2].span.0 as usize;
+                    let end = args[2].span.1 as usize;
+                    error_at(start, end, format!("expected integer, got {:?}", ty));
+                }
+                let unit = self.module.types.intern(Type::Unit);
+                let kind = ExprKind::Intrinsic(intrinsic, vec![dst, x, n]);
+                Expr { kind, ty: unit }
+            }
+            Intrinsic::Sqrt | Intrinsic::Fabs => {
+                let x = self.infer_expr(&args[0], env);
+                match self.module.types.get(x.ty) {
+                    Type::F32 | Type::F64 => {}
+                    ty => {
+                        let start = args[0].span.0 as usize;
+                        let end = args[0].span.1 as usize;
+                        error_at(start, end, format!("expected float, got {:?}", ty));
+                    }
+                }
+                let ty = x.ty;
+                let kind = ExprKind::Intrinsic(intrinsic, vec![x]);
+                Expr { kind, ty }
+            }
+            Intrinsic::Unordered => {
+                let start = args[0].span.0 as usize;
+                let end = args[0].span.1 as usize;
+                let (p, x, y) = match &args[0].kind {
+                    syntax::ExprKind::Binary(op, x, y) => match binop(*op) {
+                        Binop::Cmp(p) => (p, x, y),
+                        _ => error_at(start, end, "expected float comparison"),
+                    },
+                    _ => error_at(start, end, "expected float comparison"),
+                };
+                let x = self.build_expr(x, None);
+                match self.module.types.get(x.ty) {
+                    Type::F32 | Type::F64 => {}
+                    ty => error_at(start, end, format!("expected float, got {:?}", ty)),
+                }
+                let y = self.build_expr(y, Some(x.ty));
+                let bool = self.module.types.intern(Type::Bool);
+                let kind = ExprKind::Binary(Binop::UnorderedCmp(p), x.into(), y.into());
+                let kind = fold(&self.module.types, &kind, bool).unwrap_or(kind);
+                Expr { kind, ty: bool }
+            }
+        }
+    }
+}
+
+fn set_indirect_br_dests(block: &mut Block, dests: &[LabelId]) {
+    for stmt in &mut block.stmts {
+        match stmt {
+            Stmt::IndirectBr(_, stmt_dests) => *stmt_dests = dests.to_vec(),
+            Stmt::If(_, body, None) | Stmt::While(_, _, body) | Stmt::For(_, _, _, _, body) => {
+                set_indirect_br_dests(body, dests)
+            }
+            Stmt::If(_, body, Some(else_body)) => {
+                set_indirect_br_dests(body, dests);
+                set_indirect_br_dests(else_body, dests);
+            }
+            Stmt::Match(_, arms, default) => {
+                for (_, body) in arms {
+                    set_indirect_br_dests(body, dests);
+                }
+                if let Some(body) = default {
+                    set_indirect_br_dests(body, dests);
+                }
+            }
+            _ => {}
+        }
+    }
 }
 
 #[derive(Default)]
@@ -764,6 +1709,7 @@ struct ModuleBuilder {
     names: NameTable,
     types: TypeIntern,
     consts: Vec<Const>,
+    globals: Vec<Global>,
     func_decls: Vec<FuncDecl>,
 }
 
@@ -774,6 +1720,11 @@ impl ModuleBuilder {
         self.names.def(name, Def::Type(i));
     }
 
+    fn add_intrinsic(&mut self, name: &str, intrinsic: Intrinsic) {
+        let name = intern(name);
+        self.names.def(name, Def::Intrinsic(intrinsic));
+    }
+
     fn add_const_decl(&mut self, const_decl: &syntax::ConstDecl) -> ConstId {
         let ty = match &const_decl.ty {
             Some(ty) => Some(self.build_type(ty)),
@@ -789,49 +1740,218 @@ impl ModuleBuilder {
         id
     }
 
+    fn add_global_decl(&mut self, global_decl: &syntax::GlobalDecl) -> GlobalId {
+        let ty = match &global_decl.ty {
+            Some(ty) => Some(self.build_type(ty)),
+            None => None,
+        };
+        let init = match &global_decl.value {
+            Some(value) => Some(self.check_const_expr(value, ty)),
+            None => None,
+        };
+        let g = Global {
+            name: global_decl.name,
+            ty: ty.unwrap_or_else(|| init.as_ref().unwrap().ty),
+            init: init,
+            linkage: global_decl.linkage,
+            visibility: global_decl.visibility,
+        };
+        let id = self.globals.len();
+        self.globals.push(g);
+        id
+    }
+
     fn check_const_expr(&mut self, e: &syntax::Expr, ty: Option<TypeId>) -> Expr {
         let e = self.infer_const_expr(e, ty);
         if let Some(ty) = ty {
             if e.ty != ty {
                 let expected = self.types.get(ty);
                 let got = self.types.get(e.ty);
-                println!("expected {:?}, got {:?}", expected, got);
-                error();
+                error(format!("expected {:?}, got {:?}", expected, got));
             }
         }
         e
     }
 
     fn infer_const_expr(&mut self, e: &syntax::Expr, ty: Option<TypeId>) -> Expr {
+        let (start, end) = (e.span.0 as usize, e.span.1 as usize);
         match &e.kind {
             syntax::ExprKind::Integer(s) => {
                 let kind = ExprKind::Integer(*s);
                 let ty = match ty {
                     None => self.types.intern(Type::I32),
                     Some(ty) => match self.types.get(ty) {
-                        Type::I8 | Type::I16 | Type::I32 | Type::I64 => ty,
+                        Type::I8 | Type::I16 | Type::I32 | Type::I64 | Type::I128 => ty,
+                        Type::U8 | Type::U16 | Type::U32 | Type::U64 | Type::U128 => ty,
                         _ => self.types.intern(Type::I32),
                     },
                 };
                 Expr { kind, ty }
             }
-            e => panic!("infer const expr {:?}", e),
+            syntax::ExprKind::Float(s) => {
+                let kind = ExprKind::Float(*s);
+                let ty = match ty {
+                    Some(ty) if *self.types.get(ty) == Type::F64 => ty,
+                    _ => self.types.intern(Type::F32),
+                };
+                Expr { kind, ty }
+            }
+            syntax::ExprKind::Bool(b) => {
+                let kind = ExprKind::Bool(*b);
+                let ty = self.types.intern(Type::Bool);
+                Expr { kind, ty }
+            }
+            &syntax::ExprKind::Char(c) => {
+                let kind = ExprKind::Char(c);
+                let ty = self.types.intern(Type::I8);
+                Expr { kind, ty }
+            }
+            &syntax::ExprKind::Field(ref x, variant_name) => {
+                let ty = match x.kind {
+                    syntax::ExprKind::Name(name) => match self.names.get(name) {
+                        Some(Def::Type(ty)) => ty,
+                        _ => error_at(start, end, "expected constant expression"),
+                    },
+                    _ => error_at(start, end, "expected constant expression"),
+                };
+                let ety = match self.types.get(ty) {
+                    Type::Enum(ety) => ety,
+                    _ => error_at(start, end, "expected constant expression"),
+                };
+                let (i, variant) = match ety.variant(variant_name) {
+                    Some(v) => v,
+                    None => {
+                        let message = format!(
+                            "enum variant {:?} not found on enum type {:?}",
+                            variant_name, ety.name
+                        );
+                        error_at(start, end, message);
+                    }
+                };
+                if variant.args.len() != 0 {
+                    let message =
+                        format!("constant enum variant {:?} cannot carry data", variant_name);
+                    error_at(start, end, message);
+                }
+                let kind = ExprKind::EnumVariant(i);
+                Expr { kind, ty }
+            }
+            syntax::ExprKind::Unary(op, x) => {
+                let (op, x, ty) = match *op {
+                    syntax::MINUS => {
+                        let x = self.infer_const_expr(x, ty);
+                        let ty = x.ty;
+                        (Unop::Neg, x, ty)
+                    }
+                    syntax::TILDE => {
+                        let x = self.infer_const_expr(x, ty);
+                        let ty = x.ty;
+                        (Unop::BitNot, x, ty)
+                    }
+                    syntax::BANG => {
+                        let x = self.infer_const_expr(x, None);
+                        (Unop::LogicNot, x, self.types.intern(Type::Bool))
+                    }
+                    _ => error_at(start, end, "expected constant expression"),
+                };
+                let kind = ExprKind::Unary(op, x.into());
+                self.fold_const(e, kind, ty)
+            }
+            syntax::ExprKind::Binary(op, x, y) => {
+                let op = binop(*op);
+                let bool = self.types.intern(Type::Bool);
+                let (x, y, ty) = match op {
+                    Binop::LogicAnd | Binop::LogicOr => {
+                        let x = self.infer_const_expr(x, Some(bool));
+                        let y = self.infer_const_expr(y, Some(bool));
+                        (x, y, bool)
+                    }
+                    Binop::Cmp(_) => {
+                        let x = self.infer_const_expr(x, None);
+                        let y = self.infer_const_expr(y, Some(x.ty));
+                        (x, y, bool)
+                    }
+                    _ => {
+                        let x = self.infer_const_expr(x, ty);
+                        let y = self.infer_const_expr(y, Some(x.ty));
+                        let ty = x.ty;
+                        (x, y, ty)
+                    }
+                };
+                let kind = ExprKind::Binary(op, x.into(), y.into());
+                self.fold_const(e, kind, ty)
+            }
+            _ => error_at(start, end, "expected constant expression"),
+        }
+    }
+
+    /// Folds `kind`, the lowered form of `e`.
+    fn fold_const(&mut self, e: &syntax::Expr, kind: ExprKind, ty: TypeId) -> Expr {
+        match fold(&self.types, &kind, ty) {
+            Some(kind) => Expr { kind, ty },
+            None => {
+                let (start, end) = (e.span.0 as usize, e.span.1 as usize);
+                error_at(start, end, "constant expression overflows or is undefined");
+            }
+        }
+    }
+
+    fn array_len(&mut self, e: &syntax::Expr) -> u32 {
+        let (start, end) = (e.span.0 as usize, e.span.1 as usize);
+        let u32 = self.types.intern(Type::U32);
+        let n = self.infer_const_expr(e, Some(u32));
+        let s = match n.kind {
+            ExprKind::Integer(s) => s,
+            _ => {
+                error_at(start, end, "array element count must be an integer");
+            }
+        };
+        let (s, radix) = int_radix(&s);
+        match u32::from_str_radix(&s, radix as u32) {
+            Ok(n) => n,
+            Err(e) => {
+                let message = format!("unable to parse array element count: {}", e);
+                error_at(start, end, message);
+            }
         }
     }
 
     fn add_type_decl(&mut self, type_decl: &syntax::TypeDecl) {
         let ty = match &type_decl.kind {
             syntax::TypeDeclKind::Enum(variants) => {
-                let mut xvariants = vec![];
+                let mut xvariants: Vec<EnumVariant> = vec![];
                 for variant in variants {
                     let mut args = vec![];
                     for arg in &variant.args {
                         let arg = self.build_type(arg);
                         args.push(arg);
                     }
+                    let start = variant.span.0 as usize;
+                    let end = variant.span.1 as usize;
+                    // Numbered from the previous variant by default, like C.
+                    let tag = match (variant.discriminant, xvariants.last()) {
+                        (Some(tag), _) => tag,
+                        (None, None) => 0,
+                        (None, Some(prev)) => match prev.tag.checked_add(1) {
+                            Some(tag) => tag,
+                            None => {
+                                let message =
+                                    format!("discriminant of {:?} overflows u32", variant.name);
+                                error_at(start, end, message);
+                            }
+                        },
+                    };
+                    if let Some(other) = xvariants.iter().find(|v| v.tag == tag) {
+                        let message = format!(
+                            "{:?} and {:?} have the same discriminant {}",
+                            other.name, variant.name, tag
+                        );
+                        error_at(start, end, message);
+                    }
                     let variant = EnumVariant {
                         name: variant.name,
                         args: args,
+                        tag: tag,
                     };
                     xvariants.push(variant);
                 }
@@ -841,24 +1961,51 @@ impl ModuleBuilder {
                 };
                 self.types.intern(Type::Enum(ty))
             }
-            syntax::TypeDeclKind::Struct(fields) => {
+            &syntax::TypeDeclKind::Struct(ref fields, packed) => {
                 let mut fields2 = vec![];
-                for (name, ty) in fields {
+                for &(name, ref ty, bits) in fields {
                     let ty = self.build_type(ty);
-                    fields2.push((*name, ty));
+                    if let Some(bits) = bits {
+                        let max = match self.types.get(ty).int_bits() {
+                            Some(max) => max,
+                            None => {
+                                let ty = self.types.get(ty);
+                                error(format!(
+                                    "bit-field {:?} must be an integer, got {:?}",
+                                    name, ty
+                                ));
+                            }
+                        };
+                        if bits == 0 || bits > max {
+                            error(format!("bit-field {:?} width must be 1 to {}", name, max));
+                        }
+                    }
+                    fields2.push((name, ty, bits));
                 }
                 let sty = StructType {
                     name: type_decl.name,
                     fields: fields2,
+                    packed: packed,
                 };
                 self.types.intern(Type::Struct(sty))
             }
+            syntax::TypeDeclKind::Union(fields) => {
+                let mut fields2 = vec![];
+                for &(name, ref ty) in fields {
+                    fields2.push((name, self.build_type(ty)));
+                }
+                let uty = UnionType {
+                    name: type_decl.name,
+                    fields: fields2,
+                };
+                self.types.intern(Type::Union(uty))
+            }
             syntax::TypeDeclKind::Alias(ty) => self.build_type(ty),
         };
         self.names.def(type_decl.name, Def::Type(ty));
     }
 
-    fn add_func_decl(&mut self, func: &syntax::FuncDecl) {
+    fn add_func_decl(&mut self, func: &syntax::FuncDecl, external: bool) {
         let i = self.func_decls.len();
         self.names.def(func.name, Def::Func(i));
 
@@ -867,6 +2014,10 @@ impl ModuleBuilder {
             name: func.name,
             ty: func_type,
             params: func.params.clone(),
+            linkage: func.linkage,
+            visibility: func.visibility,
+            external: external,
+            attrs: func.attrs.clone(),
         };
         self.func_decls.push(func_decl);
     }
@@ -884,7 +2035,8 @@ impl ModuleBuilder {
             }
             syntax::Type::Name(name) => match self.names.get(*name) {
                 Some(Def::Type(i)) => i,
-                x => panic!("building type: {:?} def = {:?}", name, x),
+                Some(_) => error(format!("{:?} is not a type", name)),
+                None => error(format!("undefined type {:?}", name)),
             },
             syntax::Type::Pointer(ty) => {
                 let ty = self.build_type(ty);
@@ -902,14 +2054,16 @@ impl ModuleBuilder {
                     params,
                     ret,
                     var_args,
+                    call_conv: func.call_conv,
                 };
                 let func = Type::Func(func);
                 self.types.intern(func)
             }
             syntax::Type::Unit => self.types.intern(Type::Unit),
             syntax::Type::Array(n, elem_ty) => {
+                let n = self.array_len(n);
                 let elem_ty = self.build_type(elem_ty);
-                let array_ty = Type::Array(elem_ty, *n);
+                let array_ty = Type::Array(elem_ty, n);
                 self.types.intern(array_ty)
             }
         }
@@ -927,21 +2081,29 @@ impl ModuleBuilder {
             params,
             ret,
             var_args,
+            call_conv: ty.call_conv,
         }
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Type {
     I8,
     I16,
     I32,
     I64,
+    I128,
+    U8,
+    U16,
+    U32,
+    U64,
+    U128,
     F32,
     F64,
     Pointer(TypeId),
     Func(FuncType),
     Struct(StructType),
+    Union(UnionType),
     Tuple(Vec<TypeId>),
     Array(TypeId, u32),
     Enum(EnumType),
@@ -950,6 +2112,10 @@ pub enum Type {
 }
 
 impl Type {
+    /// Unit values have no representation and scalars are passed by value.
+    /// Aggregates are passed like C structs on x86-64: in registers when
+    /// they're small enough, otherwise in memory, with an sret pointer for
+    /// returns.
     pub fn kind(&self) -> TypeKind {
         match self {
             Type::Unit => TypeKind::Unit,
@@ -957,18 +2123,42 @@ impl Type {
             Type::I16 => TypeKind::Scalar,
             Type::I32 => TypeKind::Scalar,
             Type::I64 => TypeKind::Scalar,
+            Type::I128 => TypeKind::Scalar,
+            Type::U8 => TypeKind::Scalar,
+            Type::U16 => TypeKind::Scalar,
+            Type::U32 => TypeKind::Scalar,
+            Type::U64 => TypeKind::Scalar,
+            Type::U128 => TypeKind::Scalar,
             Type::F32 => TypeKind::Scalar,
             Type::F64 => TypeKind::Scalar,
             Type::Bool => TypeKind::Scalar,
             Type::Func(_) => TypeKind::Scalar,
             Type::Pointer(_) => TypeKind::Scalar,
             Type::Struct(_) => TypeKind::Aggregate,
+            Type::Union(_) => TypeKind::Aggregate,
             Type::Array(_, _) => TypeKind::Aggregate,
             Type::Tuple(_) => TypeKind::Aggregate,
             Type::Enum(_) => TypeKind::Aggregate,
         }
     }
 
+    pub fn int_bits(&self) -> Option<u32> {
+        match self {
+            Type::I8 => Some(8),
+            Type::I16 => Some(16),
+            Type::I32 => Some(32),
+            Type::I64 => Some(64),
+            Type::I128 => Some(128),
+            Type::U8 => Some(8),
+            Type::U16 => Some(16),
+            Type::U32 => Some(32),
+            Type::U64 => Some(64),
+            Type::U128 => Some(128),
+            _ => None,
+        }
+    }
+
+    /// Panics if the type isn't a scalar. Bool is an int, and funcs are pointers.
     pub fn scalar_kind(&self) -> ScalarKind {
         match self {
             Type::Unit => panic!(),
@@ -976,12 +2166,19 @@ impl Type {
             Type::I16 => ScalarKind::Int,
             Type::I32 => ScalarKind::Int,
             Type::I64 => ScalarKind::Int,
+            Type::I128 => ScalarKind::Int,
+            Type::U8 => ScalarKind::UInt,
+            Type::U16 => ScalarKind::UInt,
+            Type::U32 => ScalarKind::UInt,
+            Type::U64 => ScalarKind::UInt,
+            Type::U128 => ScalarKind::UInt,
             Type::Bool => ScalarKind::Int,
             Type::F32 => ScalarKind::Float,
             Type::F64 => ScalarKind::Float,
             Type::Func(_) => ScalarKind::Pointer,
             Type::Pointer(_) => ScalarKind::Pointer,
             Type::Struct(_) => panic!(),
+            Type::Union(_) => panic!(),
             Type::Array(_, _) => panic!(),
             Type::Tuple(_) => panic!(),
             Type::Enum(_) => panic!(),
@@ -993,6 +2190,7 @@ impl Type {
 pub enum ScalarKind {
     Float,
     Int,
+    UInt,
     Pointer,
 }
 
@@ -1003,16 +2201,30 @@ pub enum TypeKind {
     Scalar,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct UnionType {
+    pub name: String,
+    pub fields: Vec<(String, TypeId)>,
+}
+
+impl UnionType {
+    fn field_index(&self, field_name: String) -> Option<usize> {
+        self.fields.iter().position(|&(name, _)| name == field_name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct EnumType {
     pub name: String,
     pub variants: Vec<EnumVariant>,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct EnumVariant {
     pub name: String,
     pub args: Vec<TypeId>,
+    // The discriminant stored in the enum's tag.
+    pub tag: u32,
 }
 
 impl EnumType {
@@ -1025,17 +2237,38 @@ impl EnumType {
         }
         None
     }
+
+    /// The tag is just wide enough to hold the largest discriminant.
+    pub fn tag_bits(&self) -> u32 {
+        match self.variants.iter().map(|v| v.tag).max().unwrap_or(0) {
+            0..=1 => 1,
+            2..=0xff => 8,
+            0x100..=0xffff => 16,
+            _ => 32,
+        }
+    }
+
+    pub fn tag_type(&self) -> Type {
+        match self.tag_bits() {
+            1 => Type::Bool,
+            8 => Type::U8,
+            16 => Type::U16,
+            _ => Type::U32,
+        }
+    }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct StructType {
     pub name: String,
-    pub fields: Vec<(String, TypeId)>,
+    // name, type, bit-field width
+    pub fields: Vec<(String, TypeId, Option<u32>)>,
+    pub packed: bool,
 }
 
 impl StructType {
     fn field_index(&self, field_name: String) -> Option<usize> {
-        for (i, &(name, _)) in self.fields.iter().enumerate() {
+        for (i, &(name, _, _)) in self.fields.iter().enumerate() {
             if name == field_name {
                 return Some(i);
             }
@@ -1048,13 +2281,16 @@ pub type TypeId = usize;
 pub type ParamId = usize;
 pub type FuncId = usize;
 pub type LocalId = usize;
+pub type LabelId = usize;
 pub type ConstId = usize;
+pub type GlobalId = usize;
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct FuncType {
     pub params: Vec<TypeId>,
     pub ret: TypeId,
     pub var_args: bool,
+    pub call_conv: CallConv,
 }
 
 #[derive(Debug)]
@@ -1062,18 +2298,29 @@ pub struct FuncDecl {
     pub name: String,
     pub params: Vec<String>,
     pub ty: FuncType,
+    pub linkage: Linkage,
+    // None uses the module's default visibility.
+    pub visibility: Option<Visibility>,
+    // Declared without a body, so it's defined in another object.
+    pub external: bool,
+    pub attrs: Vec<FuncAttr>,
 }
 
 #[derive(Debug)]
 pub struct FuncBody {
     pub id: FuncId,
     pub locals: Vec<TypeId>,
+    pub labels: Vec<String>,
     pub body: Block,
+    // Source line of the definition.
+    pub line: u32,
 }
 
 #[derive(Debug)]
 pub struct Block {
     pub stmts: Vec<Stmt>,
+    // Source line of each statement.
+    pub lines: Vec<u32>,
 }
 
 #[derive(Debug)]
@@ -1081,11 +2328,17 @@ pub enum Stmt {
     Assign(Expr, Expr),
     Return(Expr),
     Expr(Expr),
-    If(Expr, Block),
-    While(Expr, Block),
-    For(Vec<Stmt>, Expr, Vec<Stmt>, Block),
-    Break,
-    Continue,
+    If(Expr, Block, Option<Block>),
+    // Switch on the enum tag: one arm per variant, plus an optional default.
+    Match(Expr, Vec<(u32, Block)>, Option<Block>),
+    // Loops can be labeled, and break/continue target the innermost loop
+    // unless they name one.
+    While(Option<String>, Expr, Block),
+    For(Option<String>, Vec<Stmt>, Expr, Vec<Stmt>, Block),
+    Break(Option<String>),
+    Continue(Option<String>),
+    Label(LabelId),
+    IndirectBr(Expr, Vec<LabelId>),
 }
 
 #[derive(Debug, Clone)]
@@ -1108,18 +2361,52 @@ pub enum Predicate {
 pub enum Unop {
     AddressOf,
     Deref,
+    Neg,
+    BitNot,
+    LogicNot,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum Intrinsic {
+    // Read the processor's cycle counter (x86 only).
+    Rdtsc,
+    // Integer add/sub that clamp to the type's range instead of wrapping.
+    SatAdd,
+    SatSub,
+    // Integer add/sub/mul that trap on overflow instead of wrapping.
+    CheckedAdd,
+    CheckedSub,
+    CheckedMul,
+    // Truncate an integer to a narrower integer type.
+    Trunc,
+    // memcpy(dst, src, n) and memset(dst, byte, n), n in bytes.
+    Memcpy,
+    Memset,
+    Sqrt,
+    Fabs,
+    // unordered(x < y) compares floats like x < y, but is also true if
+    // either is NaN.
+    Unordered,
 }
 
 #[derive(Debug, Copy, Clone)]
 pub enum Binop {
     And,
+    Or,
+    Xor,
+    // Short-circuiting, only evaluates the rhs if needed.
+    LogicAnd,
+    LogicOr,
     Add,
     Sub,
     Mul,
     Div,
+    Rem,
     Shl,
     Shr,
     Cmp(Predicate),
+    // Float comparison that is true if either operand is NaN.
+    UnorderedCmp(Predicate),
 }
 
 #[derive(Debug, Clone)]
@@ -1132,6 +2419,7 @@ pub enum ExprKind {
     Param(ParamId),
     Func(FuncId),
     Local(LocalId),
+    Global(GlobalId),
     Type(TypeId),
     Unary(Unop, Box<Expr>),
     Binary(Binop, Box<Expr>, Box<Expr>),
@@ -1139,19 +2427,35 @@ pub enum ExprKind {
     Call(Box<Expr>, Vec<Expr>),
     Struct(Vec<(u32, Expr)>),
     Array(Vec<Expr>),
+    // [value; count], with value built once.
+    ArrayRepeat(Box<Expr>, u32),
     Tuple(Vec<Expr>),
     Field(Box<Expr>, u32),
     Index(Box<Expr>, Box<Expr>),
     Cast(Box<Expr>, TypeId),
     Bool(bool),
     Char(u8),
+    // Size in bytes including tail padding, like C; always i64.
     Sizeof(TypeId),
+    // ABI alignment in bytes; always i64.
+    Alignof(TypeId),
+    // A variant's index, or its discriminant when typed as the enum's tag.
     EnumVariant(u32),
     EnumCall(u32, Vec<Expr>),
     // target, enum variant, field index
     EnumField(Box<Expr>, u32, u32),
     // Read enum tag from expr
     EnumTag(Box<Expr>),
+    Intrinsic(Intrinsic, Vec<Expr>),
+    LabelAddr(LabelId),
+    Cond(Box<Expr>, Box<Expr>, Box<Expr>),
+    // Output operands are places and come first, their constraints start with '='.
+    InlineAsm {
+        template: String,
+        constraints: Vec<String>,
+        operands: Vec<Expr>,
+        clobbers: Vec<String>,
+    },
 }
 
 pub fn print(module: &Module2) {
@@ -1168,3 +2472,313 @@ pub fn print(module: &Module2) {
         }
     }
 }
+
+fn binop(op: syntax::Token) -> Binop {
+    match op {
+        syntax::PLUS => Binop::Add,
+        syntax::MINUS => Binop::Sub,
+        syntax::STAR => Binop::Mul,
+        syntax::SLASH => Binop::Div,
+        syntax::PERCENT => Binop::Rem,
+        syntax::LT => Binop::Cmp(Predicate::Lt),
+        syntax::GT => Binop::Cmp(Predicate::Gt),
+        syntax::LE => Binop::Cmp(Predicate::Le),
+        syntax::GE => Binop::Cmp(Predicate::Ge),
+        syntax::EQ => Binop::Cmp(Predicate::Eq),
+        syntax::NE => Binop::Cmp(Predicate::Ne),
+        syntax::AND => Binop::And,
+        syntax::AMPERSAND => Binop::And,
+        syntax::PIPE => Binop::Or,
+        syntax::CARET => Binop::Xor,
+        syntax::ANDAND => Binop::LogicAnd,
+        syntax::OROR => Binop::LogicOr,
+        syntax::LSHIFT => Binop::Shl,
+        syntax::RSHIFT => Binop::Shr,
+        _ => panic!(),
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+enum Literal {
+    Int(i128),
+    Float(f64),
+    Bool(bool),
+}
+
+/// The smallest and largest values of an integer type. u128 is capped at
+/// i128::MAX.
+fn int_range(ty: &Type) -> Option<(i128, i128)> {
+    let bits = ty.int_bits()?;
+    let range = match (ty.scalar_kind(), bits) {
+        (ScalarKind::Int, 128) => (i128::MIN, i128::MAX),
+        (ScalarKind::Int, _) => (-(1 << (bits - 1)), (1 << (bits - 1)) - 1),
+        (_, 128) => (0, i128::MAX),
+        (_, _) => (0, (1 << bits) - 1),
+    };
+    Some(range)
+}
+
+/// The value of a literal, if it fits its type.
+fn literal(types: &TypeIntern, e: &Expr) -> Option<Literal> {
+    let ty = types.get(e.ty);
+    match &e.kind {
+        ExprKind::Integer(s) => {
+            let (s, radix) = int_radix(s);
+            let v = i128::from_str_radix(&s, radix as u32).ok()?;
+            let (min, max) = int_range(ty)?;
+            if v < min || v > max {
+                return None;
+            }
+            Some(Literal::Int(v))
+        }
+        &ExprKind::Char(c) => Some(Literal::Int(c as i8 as i128)),
+        ExprKind::Float(s) => {
+            let s = s.replace('_', "");
+            let v = match ty {
+                Type::F32 => s.parse::<f32>().ok()? as f64,
+                _ => s.parse::<f64>().ok()?,
+            };
+            Some(Literal::Float(v))
+        }
+        &ExprKind::Bool(b) => Some(Literal::Bool(b)),
+        _ => None,
+    }
+}
+
+/// Evaluates a unary or binary op on literals into a literal of type `ty`.
+/// Only well-defined ops are folded: integer results must fit their type,
+/// shifts must be less than the width, and float results must be finite.
+fn fold(types: &TypeIntern, kind: &ExprKind, ty: TypeId) -> Option<ExprKind> {
+    use Literal::*;
+    let f32 = *types.get(ty) == Type::F32;
+    // f32 ops are done in f32, so they round the way they would at runtime.
+    let float = |x: f64, y: f64, f: fn(f64, f64) -> f64, g: fn(f32, f32) -> f32| match f32 {
+        true => g(x as f32, y as f32) as f64,
+        false => f(x, y),
+    };
+    let v = match kind {
+        ExprKind::Unary(op, x) => match (op, literal(types, x)?) {
+            (Unop::Neg, Int(x)) => Int(x.checked_neg()?),
+            (Unop::Neg, Float(x)) => Float(-x),
+            (Unop::BitNot, Int(x)) => match types.get(ty).scalar_kind() {
+                ScalarKind::Int => Int(!x),
+                _ if types.get(ty).int_bits()? == 128 => return None,
+                _ => Int(int_range(types.get(ty))?.1 - x),
+            },
+            (Unop::LogicNot, Bool(x)) => Bool(!x),
+            (Unop::LogicNot, Int(x)) => Bool(x == 0),
+            _ => return None,
+        },
+        ExprKind::Binary(op, x, y) => match (op, literal(types, x)?, literal(types, y)?) {
+            (Binop::Add, Int(x), Int(y)) => Int(x.checked_add(y)?),
+            (Binop::Sub, Int(x), Int(y)) => Int(x.checked_sub(y)?),
+            (Binop::Mul, Int(x), Int(y)) => Int(x.checked_mul(y)?),
+            (Binop::Div, Int(x), Int(y)) => Int(x.checked_div(y)?),
+            (Binop::Rem, Int(x), Int(y)) => Int(x.checked_rem(y)?),
+            (Binop::And, Int(x), Int(y)) => Int(x & y),
+            (Binop::Or, Int(x), Int(y)) => Int(x | y),
+            (Binop::Xor, Int(x), Int(y)) => Int(x ^ y),
+            (Binop::Shl, Int(x), Int(y)) | (Binop::Shr, Int(x), Int(y)) => {
+                let bits = types.get(ty).int_bits()? as i128;
+                if y < 0 || y >= bits.min(127) {
+                    return None;
+                }
+                match op {
+                    Binop::Shl => Int(x.checked_mul(1 << y)?),
+                    _ => Int(x >> y),
+                }
+            }
+            (&Binop::Cmp(p), Int(x), Int(y)) => Bool(compare(p, x, y)),
+            (&Binop::Cmp(p), Float(x), Float(y)) => Bool(compare(p, x, y)),
+            (&Binop::UnorderedCmp(p), Float(x), Float(y)) => {
+                Bool(x.is_nan() || y.is_nan() || compare(p, x, y))
+            }
+            (Binop::Add, Float(x), Float(y)) => Float(float(x, y, |x, y| x + y, |x, y| x + y)),
+            (Binop::Sub, Float(x), Float(y)) => Float(float(x, y, |x, y| x - y, |x, y| x - y)),
+            (Binop::Mul, Float(x), Float(y)) => Float(float(x, y, |x, y| x * y, |x, y| x * y)),
+            (Binop::Div, Float(x), Float(y)) => Float(float(x, y, |x, y| x / y, |x, y| x / y)),
+            (Binop::Rem, Float(x), Float(y)) => Float(float(x, y, |x, y| x % y, |x, y| x % y)),
+            (Binop::And, Bool(x), Bool(y)) | (Binop::LogicAnd, Bool(x), Bool(y)) => Bool(x && y),
+            (Binop::Or, Bool(x), Bool(y)) | (Binop::LogicOr, Bool(x), Bool(y)) => Bool(x || y),
+            (Binop::Xor, Bool(x), Bool(y)) => Bool(x != y),
+            (Binop::Cmp(Predicate::Eq), Bool(x), Bool(y)) => Bool(x == y),
+            (Binop::Cmp(Predicate::Ne), Bool(x), Bool(y)) => Bool(x != y),
+            _ => return None,
+        },
+        _ => return None,
+    };
+    let kind = match v {
+        Int(v) => {
+            let (min, max) = int_range(types.get(ty))?;
+            if v < min || v > max {
+                return None;
+            }
+            ExprKind::Integer(intern(&v.to_string()))
+        }
+        Float(v) if !v.is_finite() => return None,
+        Float(v) if f32 => ExprKind::Float(intern(&format!("{:?}", v as f32))),
+        Float(v) => ExprKind::Float(intern(&format!("{:?}", v))),
+        Bool(b) => ExprKind::Bool(b),
+    };
+    Some(kind)
+}
+
+/// Whether `e` has an address to store to, like the lhs of an assignment.
+fn is_place(types: &TypeIntern, e: &Expr) -> bool {
+    match &e.kind {
+        ExprKind::Local(_) | ExprKind::Param(_) | ExprKind::Global(_) => true,
+        ExprKind::Unary(Unop::Deref, _) => true,
+        ExprKind::Field(x, _) | ExprKind::Index(x, _) => match types.get(x.ty) {
+            Type::Pointer(_) => true,
+            _ => is_place(types, x),
+        },
+        _ => false,
+    }
+}
+
+/// Scalar conversions: bools, ints, and floats convert to each other, and
+/// pointers convert to pointers and ints.
+fn castable(from: &Type, to: &Type) -> bool {
+    let is_number = |ty: &Type| ty.int_bits().is_some() || *ty == Type::Bool;
+    let is_float = |ty: &Type| *ty == Type::F32 || *ty == Type::F64;
+    match (from, to) {
+        (Type::Pointer(_), Type::Pointer(_)) => true,
+        (Type::Pointer(_), y) => y.int_bits().is_some(),
+        (x, Type::Pointer(_)) => x.int_bits().is_some(),
+        (x, y) if is_number(x) && is_number(y) => true,
+        (x, y) if is_float(x) && is_float(y) => true,
+        (x, y) => is_float(x) && y.int_bits().is_some() || x.int_bits().is_some() && is_float(y),
+    }
+}
+
+fn compare<T: PartialOrd>(p: Predicate, x: T, y: T) -> bool {
+    match p {
+        Predicate::Eq => x == y,
+        Predicate::Ne => x != y,
+        Predicate::Le => x <= y,
+        Predicate::Ge => x >= y,
+        Predicate::Lt => x < y,
+        Predicate::Gt => x > y,
+    }
+}
+
+/// Splits a `0x`/`0o`/`0b` prefix off an integer literal, keeping its sign.
+/// `_` separators are dropped.
+pub fn int_radix(s: &str) -> (std::string::String, u8) {
+    let s = s.replace('_', "");
+    let (sign, digits, radix) = syntax::split_radix(&s);
+    (format!("{}{}", sign, digits), radix as u8)
+}
+
+/// Returns bytes, since a `\xNN` escape needn't be valid UTF-8. The lexer
+/// has already checked the hex digits.
+pub fn unescape(s: &str) -> Vec<u8> {
+    let s = &s.as_bytes()[1..s.len() - 1];
+    let mut x = Vec::with_capacity(s.len());
+    let mut i = 0;
+    while i < s.len() {
+        let c = s[i];
+        i += 1;
+        if c != b'\\' {
+            x.push(c);
+            continue;
+        }
+        let c = s[i];
+        i += 1;
+        let c = match c {
+            b'n' => b'\n',
+            b't' => b'\t',
+            b'r' => b'\r',
+            b'0' => 0,
+            b'x' => {
+                let hex = std::str::from_utf8(&s[i..i + 2]).unwrap();
+                i += 2;
+                u8::from_str_radix(hex, 16).unwrap()
+            }
+            // Backslashes and quotes escape to themselves.
+            c => c,
+        };
+        x.push(c);
+    }
+    x
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn type_kinds() {
+        let ints = [Type::I8, Type::I16, Type::I32, Type::I64, Type::I128];
+        for ty in &ints {
+            assert_eq!(ty.kind(), TypeKind::Scalar);
+            assert_eq!(ty.scalar_kind(), ScalarKind::Int);
+        }
+        let uints = [Type::U8, Type::U16, Type::U32, Type::U64, Type::U128];
+        for ty in &uints {
+            assert_eq!(ty.kind(), TypeKind::Scalar);
+            assert_eq!(ty.scalar_kind(), ScalarKind::UInt);
+        }
+        for ty in &[Type::F32, Type::F64] {
+            assert_eq!(ty.kind(), TypeKind::Scalar);
+            assert_eq!(ty.scalar_kind(), ScalarKind::Float);
+        }
+        assert_eq!(Type::Bool.kind(), TypeKind::Scalar);
+        assert_eq!(Type::Bool.scalar_kind(), ScalarKind::Int);
+
+        let func = Type::Func(FuncType {
+            params: vec![],
+            ret: 0,
+            var_args: false,
+            call_conv: CallConv::C,
+        });
+        for ty in &[Type::Pointer(0), func] {
+            assert_eq!(ty.kind(), TypeKind::Scalar);
+            assert_eq!(ty.scalar_kind(), ScalarKind::Pointer);
+        }
+
+        assert_eq!(Type::Unit.kind(), TypeKind::Unit);
+    }
+
+    fn aggregates() -> Vec<Type> {
+        let name = intern("t");
+        vec![
+            Type::Struct(StructType {
+                name: name,
+                fields: vec![],
+                packed: false,
+            }),
+            Type::Union(UnionType {
+                name: name,
+                fields: vec![],
+            }),
+            Type::Tuple(vec![]),
+            Type::Array(0, 4),
+            Type::Enum(EnumType {
+                name: name,
+                variants: vec![],
+            }),
+        ]
+    }
+
+    #[test]
+    fn aggregate_kinds() {
+        for ty in aggregates() {
+            assert_eq!(ty.kind(), TypeKind::Aggregate);
+        }
+    }
+
+    #[test]
+    fn aggregates_have_no_scalar_kind() {
+        for ty in aggregates() {
+            let result = std::panic::catch_unwind(|| ty.scalar_kind());
+            assert!(result.is_err(), "{:?}", ty);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn unit_has_no_scalar_kind() {
+        Type::Unit.scalar_kind();
+    }
+}
@@ -0,0 +1,120 @@
+//! The mylang compiler as a library: `syntax` parses source text, `ir` type
+//! checks it into a Module2 (or builds one directly with Module2Builder), and
+//! `llvm` compiles a Module2 to LLVM IR and object code.
+
+use std::fmt;
+use std::ops::Deref;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Mutex;
+
+pub mod ir;
+pub mod llvm;
+pub mod syntax;
+
+/// An error in the program being compiled.
+#[derive(Debug)]
+pub struct CompileError {
+    pub message: std::string::String,
+    // Byte offsets of the source text the error points at.
+    pub span: Option<(usize, usize)>,
+}
+
+/// Reports an error in the program being compiled. It unwinds to the
+/// enclosing `catch_errors` instead of exiting, so nothing after the error
+/// is built.
+pub fn error(message: impl Into<std::string::String>) -> ! {
+    let e = CompileError {
+        message: message.into(),
+        span: None,
+    };
+    raise(e)
+}
+
+/// Like `error`, pointing at `start..end` in the source text.
+pub fn error_at(start: usize, end: usize, message: impl Into<std::string::String>) -> ! {
+    let e = CompileError {
+        message: message.into(),
+        span: Some((start, end)),
+    };
+    raise(e)
+}
+
+/// Reports `e` like `error` does, e.g. to pass on one from `catch_errors`.
+pub fn raise(e: CompileError) -> ! {
+    panic::resume_unwind(Box::new(e))
+}
+
+/// Runs `f`, returning the first error it reports. Other panics are
+/// compiler bugs and keep unwinding.
+pub fn catch_errors<T>(f: impl FnOnce() -> T) -> Result<T, CompileError> {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(x) => Ok(x),
+        Err(payload) => match payload.downcast::<CompileError>() {
+            Ok(e) => Err(*e),
+            Err(payload) => panic::resume_unwind(payload),
+        },
+    }
+}
+
+/// Parses and type checks `text`.
+pub fn lower(text: &str) -> Result<ir::Module2, CompileError> {
+    catch_errors(|| ir::build(&syntax::parse(text)))
+}
+
+/// Compiles `text` to the object file `out_path`: everything between reading
+/// the source and linking. `source_file` names the source in debug info.
+pub fn compile(
+    text: &str,
+    source_file: &str,
+    options: &llvm::Options,
+    out_path: &str,
+) -> Result<(), CompileError> {
+    let module = lower(text)?;
+    catch_errors(|| unsafe {
+        let (machine, llmodule) = llvm::build(&module, source_file, options);
+        llvm::finish(llmodule.0, options);
+        if let Err(msg) = llvm::emit_object(machine.0, llmodule.0, out_path) {
+            error(msg);
+        }
+    })
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Default)]
+pub struct String(u16);
+
+impl Deref for String {
+    type Target = str;
+    fn deref(&self) -> &str {
+        let i = self.0 as usize;
+        INTERN.lock().unwrap()[i]
+    }
+}
+
+impl fmt::Debug for String {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.deref())
+    }
+}
+
+impl fmt::Display for String {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.deref())
+    }
+}
+
+// Interned strings are never freed, so they're leaked to hand out &'static
+// str without holding the lock.
+static INTERN: Mutex<Vec<&'static str>> = Mutex::new(Vec::new());
+
+pub fn intern(s: &str) -> String {
+    let mut intern = INTERN.lock().unwrap();
+    for (i, &interned) in intern.iter().enumerate() {
+        let i = i as u16;
+        if s == interned {
+            return String(i);
+        }
+    }
+    let i = intern.len() as u16;
+    intern.push(Box::leak(s.into()));
+    String(i)
+}
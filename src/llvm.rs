@@ -1,6 +1,10 @@
+use crate::catch_errors;
 use crate::error;
 use crate::ir::*;
+use crate::raise;
+use crate::CompileError;
 use llvm_sys::*;
+use std::collections::HashMap;
 use std::ffi::CStr;
 use std::mem::MaybeUninit;
 use std::ops::Deref;
@@ -12,87 +16,801 @@ macro_rules! cstr {
     }};
 }
 
-pub unsafe fn build(module: &Module2) -> (LLVMTargetMachineRef, LLVMModuleRef) {
-    LLVMInitializeX86TargetInfo();
-    LLVMInitializeX86Target();
-    LLVMInitializeX86TargetMC();
-    LLVMInitializeX86AsmPrinter();
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum OptLevel {
+    None,
+    Less,
+    Default,
+    Aggressive,
+}
+
+impl Default for OptLevel {
+    fn default() -> Self {
+        OptLevel::None
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum RelocMode {
+    Default,
+    Static,
+    Pic,
+}
+
+impl Default for RelocMode {
+    fn default() -> Self {
+        RelocMode::Default
+    }
+}
+
+#[derive(Default)]
+pub struct Options {
+    // Renames functions defined in the module; external declarations keep their names.
+    pub name_mangler: Option<Box<dyn Fn(&str) -> std::string::String>>,
+    // Place each defined function in its own `.text.<name>` section.
+    pub function_sections: bool,
+    pub opt_level: OptLevel,
+    // Target triple to cross-compile for, instead of the host's.
+    pub triple: Option<std::string::String>,
+    // Defaults to "generic" with no extra features.
+    pub cpu: Option<std::string::String>,
+    pub features: Option<std::string::String>,
+    pub reloc_mode: RelocMode,
+    // Emit DWARF line tables, like `-gline-tables-only`.
+    pub debug_info: bool,
+    // Integer overflow wraps, like `-fwrapv`, instead of being undefined.
+    pub wrapping: bool,
+    // Trap on dereferencing a null pointer instead of loading through it.
+    pub null_checks: bool,
+    // Trap on indexing a fixed-size array out of bounds.
+    pub bounds_checks: bool,
+    // Symbols to link external functions to, on top of the target's own renames.
+    pub link_names: HashMap<std::string::String, std::string::String>,
+    // Visibility of definitions that don't give one, like `-fvisibility`.
+    pub visibility: Visibility,
+}
 
-    let triple = LLVMGetDefaultTargetTriple();
+impl Options {
+    /// Targets the host's CPU and features, like `-march=native`.
+    pub unsafe fn native_cpu(&mut self) {
+        let cpu = LLVMGetHostCPUName();
+        self.cpu = Some(CStr::from_ptr(cpu).to_string_lossy().into_owned());
+        LLVMDisposeMessage(cpu);
+        let features = LLVMGetHostCPUFeatures();
+        self.features = Some(CStr::from_ptr(features).to_string_lossy().into_owned());
+        LLVMDisposeMessage(features);
+    }
+}
+
+pub unsafe fn build(
+    module: &Module2,
+    source_file: &str,
+    options: &Options,
+) -> (TargetMachine, Module) {
+    let (machine, llmodule, mut errors) = build_partial(module, source_file, options);
+    if errors.is_empty() {
+        return (machine, llmodule);
+    }
+    let mut e = errors.remove(0);
+    for other in errors {
+        e.message.push('\n');
+        e.message.push_str(&other.message);
+    }
+    raise(e)
+}
+
+/// Like `build`, but a function that reports an error is left as a
+/// declaration and the rest are still built. Their errors are returned
+/// instead of reported.
+pub unsafe fn build_partial(
+    module: &Module2,
+    source_file: &str,
+    options: &Options,
+) -> (TargetMachine, Module, Vec<CompileError>) {
+    let mut triple = match &options.triple {
+        Some(triple) => triple.clone(),
+        None => {
+            let triple = LLVMGetDefaultTargetTriple();
+            let s = CStr::from_ptr(triple).to_string_lossy().into_owned();
+            LLVMDisposeMessage(triple);
+            s
+        }
+    };
+    init_targets(&triple);
+    let sysv = triple.starts_with("x86_64") && !triple.contains("windows");
+    let mut link_names = target_link_names(&triple);
+    for (name, link_name) in &options.link_names {
+        link_names.insert(name.clone(), link_name.clone());
+    }
+    triple.push('\0');
+    let triple = triple.as_ptr() as *const i8;
     let mut target = MaybeUninit::uninit().assume_init();
     let mut err = MaybeUninit::uninit().assume_init();
     if LLVMGetTargetFromTriple(triple, &mut target, &mut err) != 0 {
-        let err = CStr::from_ptr(err);
-        println!("error getting llvm target: {:?}", err);
-        error();
+        let msg = format!("error getting llvm target: {:?}", CStr::from_ptr(err));
+        LLVMDisposeMessage(err);
+        error(msg);
     }
 
-    let cpu = cstr!("generic");
-    let features = cstr!("");
-    let machine = LLVMCreateTargetMachine(
+    let opt_level = match options.opt_level {
+        OptLevel::None => LLVMCodeGenOptLevel_LLVMCodeGenLevelNone,
+        OptLevel::Less => LLVMCodeGenOptLevel_LLVMCodeGenLevelLess,
+        OptLevel::Default => LLVMCodeGenOptLevel_LLVMCodeGenLevelDefault,
+        OptLevel::Aggressive => LLVMCodeGenOptLevel_LLVMCodeGenLevelAggressive,
+    };
+    let reloc_mode = match options.reloc_mode {
+        RelocMode::Default => LLVMRelocMode_LLVMRelocDefault,
+        RelocMode::Static => LLVMRelocMode_LLVMRelocStatic,
+        RelocMode::Pic => LLVMRelocMode_LLVMRelocPIC,
+    };
+    let mut cpu = options.cpu.clone().unwrap_or("generic".into());
+    cpu.push('\0');
+    let cpu = cpu.as_ptr() as *const i8;
+    let mut features = options.features.clone().unwrap_or_default();
+    features.push('\0');
+    let features = features.as_ptr() as *const i8;
+    // Guards are taken as soon as things are created, so an error partway
+    // through doesn't leak them.
+    let machine_guard = TargetMachine(LLVMCreateTargetMachine(
         target,
         triple,
         cpu,
         features,
-        LLVMCodeGenOptLevel_LLVMCodeGenLevelNone,
-        LLVMRelocMode_LLVMRelocDefault,
+        opt_level,
+        reloc_mode,
         LLVMCodeModel_LLVMCodeModelDefault,
-    );
-    let layout = LLVMCreateTargetDataLayout(machine);
-
-    let llmodule = LLVMModuleCreateWithName(cstr!("a"));
+    ));
+    let machine = machine_guard.0;
+    let layout_guard = TargetData(LLVMCreateTargetDataLayout(machine));
+    let layout = layout_guard.0;
+
+    let module_guard = Module(LLVMModuleCreateWithName(cstr!("a")));
+    let llmodule = module_guard.0;
+    let name = source_file.as_ptr() as *const i8;
+    LLVMSetSourceFileName(llmodule, name, source_file.len());
     LLVMSetModuleDataLayout(llmodule, layout);
     LLVMSetTarget(llmodule, triple);
 
-    let b = LLVMCreateBuilder();
-    let type_bld = &TypeBuilder::new(layout, &module.types);
+    let builder_guard = Builder(LLVMCreateBuilder());
+    let b = builder_guard.0;
+    let type_bld = &TypeBuilder::new(layout, &module.types, sysv);
     let llconsts = &build_consts(type_bld, &module.consts);
+    let llglobals = &build_globals(llmodule, type_bld, &module.globals, options);
+    let debug_info = match options.debug_info {
+        true => {
+            let dib = LLVMCreateDIBuilder(llmodule);
+            Some((dib, build_compile_unit(llmodule, dib, source_file)))
+        }
+        false => None,
+    };
+    let _dib_guard = debug_info.map(|(dib, _)| DIBuilder(dib));
 
     let mut llfuncs = vec![];
     for func_decl in &module.func_decls {
-        let lltype = type_bld.func_type(&func_decl.ty);
+        let (lltype, layout) = type_bld.func_sig(&func_decl.ty);
         let mut name = func_decl.name.deref().to_string();
+        if !func_decl.external {
+            if let Some(mangle) = &options.name_mangler {
+                name = mangle(&name);
+            }
+        } else if let Some(link_name) = link_names.get(&name) {
+            name = link_name.clone();
+        }
         name.push('\0');
-        let mut link_name = name.as_ptr() as *const i8;
-        if cfg!(target_os = "macos") && name == "readdir\0" {
-            link_name = "readdir$INODE64\0".as_ptr() as *const i8;
+        let llfunc = LLVMAddFunction(llmodule, name.as_ptr() as *const i8, lltype);
+        for &(i, attr) in &layout.attrs {
+            LLVMAddAttributeAtIndex(llfunc, i, attr);
+        }
+        for attr in &func_decl.attrs {
+            let name = match attr {
+                FuncAttr::NoInline => "noinline",
+                FuncAttr::AlwaysInline => "alwaysinline",
+                FuncAttr::Cold => "cold",
+                FuncAttr::NoReturn => "noreturn",
+            };
+            LLVMAddAttributeAtIndex(llfunc, LLVMAttributeFunctionIndex, enum_attr(name));
         }
-        let llfunc = LLVMAddFunction(llmodule, link_name, lltype);
+        LLVMSetFunctionCallConv(llfunc, call_conv(func_decl.ty.call_conv));
+        let defined = !func_decl.external;
+        set_linkage(
+            llfunc,
+            func_decl.linkage,
+            func_decl.visibility,
+            defined,
+            options,
+        );
         llfuncs.push(llfunc);
     }
     let llfuncs = &llfuncs;
 
+    let mut errors = vec![];
     for func_body in &module.func_bodys {
         let func_decl = &module.func_decls[func_body.id];
-        build_func_body(b, type_bld, llfuncs, llconsts, func_decl, func_body);
+        let llfunc = llfuncs[func_body.id];
+        if options.function_sections {
+            let name = CStr::from_ptr(LLVMGetValueName(llfunc));
+            let mut section = format!(".text.{}", name.to_string_lossy());
+            section.push('\0');
+            LLVMSetSection(llfunc, section.as_ptr() as *const i8);
+        }
+        let scope = match debug_info {
+            Some((dib, file)) => build_subprogram(dib, file, llfunc, func_decl, func_body.line),
+            None => ptr::null_mut(),
+        };
+        let result = catch_errors(|| {
+            build_func_body(
+                b,
+                type_bld,
+                llfuncs,
+                llconsts,
+                llglobals,
+                func_decl,
+                func_body,
+                scope,
+                options.wrapping,
+                options.null_checks,
+                options.bounds_checks,
+            )
+        });
+        if let Err(e) = result {
+            delete_body(llfunc);
+            errors.push(e);
+        }
+    }
+
+    if let Some((dib, _)) = debug_info {
+        LLVMDIBuilderFinalize(dib);
+    }
+    (machine_guard, module_guard, errors)
+}
+
+/// Deletes the body of a partly built function, leaving a declaration.
+unsafe fn delete_body(llfunc: LLVMValueRef) {
+    // Values can be used in other blocks, so every use is dropped before
+    // anything is deleted.
+    let mut block = LLVMGetFirstBasicBlock(llfunc);
+    while !block.is_null() {
+        let mut inst = LLVMGetFirstInstruction(block);
+        while !inst.is_null() {
+            if !LLVMGetFirstUse(inst).is_null() {
+                LLVMReplaceAllUsesWith(inst, LLVMGetUndef(LLVMTypeOf(inst)));
+            }
+            inst = LLVMGetNextInstruction(inst);
+        }
+        block = LLVMGetNextBasicBlock(block);
+    }
+    let mut block = LLVMGetFirstBasicBlock(llfunc);
+    while !block.is_null() {
+        let next = LLVMGetNextBasicBlock(block);
+        LLVMDeleteBasicBlock(block);
+        block = next;
+    }
+    // Declarations can't have local linkage or a subprogram.
+    LLVMSetLinkage(llfunc, LLVMLinkage_LLVMExternalLinkage);
+    LLVMSetVisibility(llfunc, LLVMVisibility_LLVMDefaultVisibility);
+    LLVMSetSubprogram(llfunc, ptr::null_mut());
+}
+
+/// Applies a symbol's linkage and visibility. Definitions without a visibility
+/// get the default from `options`, declarations keep LLVM's default.
+unsafe fn set_linkage(
+    llvalue: LLVMValueRef,
+    linkage: Linkage,
+    visibility: Option<Visibility>,
+    defined: bool,
+    options: &Options,
+) {
+    match linkage {
+        Linkage::External => {}
+        // Local symbols always have default visibility.
+        Linkage::Internal => {
+            LLVMSetLinkage(llvalue, LLVMLinkage_LLVMInternalLinkage);
+            return;
+        }
+        Linkage::Weak | Linkage::LinkOnceOdr => {
+            // The comdat lets the linker keep a single copy of the definition.
+            let llmodule = LLVMGetGlobalParent(llvalue);
+            let comdat = LLVMGetOrInsertComdat(llmodule, LLVMGetValueName(llvalue));
+            LLVMSetComdat(llvalue, comdat);
+            let linkage = match linkage {
+                Linkage::Weak => LLVMLinkage_LLVMWeakAnyLinkage,
+                _ => LLVMLinkage_LLVMLinkOnceODRLinkage,
+            };
+            LLVMSetLinkage(llvalue, linkage);
+        }
+    }
+    let visibility = match visibility {
+        Some(visibility) => visibility,
+        None if defined => options.visibility,
+        None => Visibility::Default,
+    };
+    let visibility = match visibility {
+        Visibility::Default => LLVMVisibility_LLVMDefaultVisibility,
+        Visibility::Hidden => LLVMVisibility_LLVMHiddenVisibility,
+        Visibility::Protected => LLVMVisibility_LLVMProtectedVisibility,
+    };
+    LLVMSetVisibility(llvalue, visibility);
+}
+
+/// Initializes the targets needed to compile for `triple`: just x86 when
+/// that's enough, since initializing every target is slower.
+unsafe fn init_targets(triple: &str) {
+    if is_x86(triple) {
+        LLVMInitializeX86TargetInfo();
+        LLVMInitializeX86Target();
+        LLVMInitializeX86TargetMC();
+        LLVMInitializeX86AsmPrinter();
+        // Needed to emit inline asm.
+        LLVMInitializeX86AsmParser();
+    } else {
+        LLVMInitializeAllTargetInfos();
+        LLVMInitializeAllTargets();
+        LLVMInitializeAllTargetMCs();
+        LLVMInitializeAllAsmPrinters();
+        LLVMInitializeAllAsmParsers();
+    }
+}
+
+fn is_x86(triple: &str) -> bool {
+    let arches = ["x86_64", "i386", "i586", "i686"];
+    arches.iter().any(|arch| triple.starts_with(arch))
+}
+
+/// Symbols that the target's libc exports under a different name than its
+/// headers declare.
+fn target_link_names(triple: &str) -> HashMap<std::string::String, std::string::String> {
+    let mut names = HashMap::new();
+    // x86_64 macOS keeps the 32-bit inode versions under the plain names.
+    if triple.starts_with("x86_64") && triple.contains("apple") {
+        for name in &["readdir", "stat", "fstat", "lstat"] {
+            names.insert(name.to_string(), format!("{}$INODE64", name));
+        }
+    }
+    names
+}
+
+/// Returns the file that subprograms are declared in.
+unsafe fn build_compile_unit(
+    llmodule: LLVMModuleRef,
+    dib: LLVMDIBuilderRef,
+    source_file: &str,
+) -> LLVMMetadataRef {
+    let dir = std::env::current_dir().unwrap_or_default();
+    let dir = dir.to_string_lossy();
+    let file = LLVMDIBuilderCreateFile(
+        dib,
+        source_file.as_ptr() as *const i8,
+        source_file.len(),
+        dir.as_ptr() as *const i8,
+        dir.len(),
+    );
+    let producer = "mylangc";
+    LLVMDIBuilderCreateCompileUnit(
+        dib,
+        LLVMDWARFSourceLanguage_LLVMDWARFSourceLanguageC99,
+        file,
+        producer.as_ptr() as *const i8,
+        producer.len(),
+        0,
+        cstr!(""),
+        0,
+        0,
+        cstr!(""),
+        0,
+        LLVMDWARFEmissionKind_LLVMDWARFEmissionLineTablesOnly,
+        0,
+        1,
+        0,
+        cstr!(""),
+        0,
+        cstr!(""),
+        0,
+    );
+    let flag = |n: u32| LLVMValueAsMetadata(LLVMConstInt(LLVMInt32Type(), n as u64, 0));
+    let warning = LLVMModuleFlagBehavior_LLVMModuleFlagBehaviorWarning;
+    let version = flag(LLVMDebugMetadataVersion());
+    LLVMAddModuleFlag(llmodule, warning, cstr!("Debug Info Version"), 18, version);
+    LLVMAddModuleFlag(llmodule, warning, cstr!("Dwarf Version"), 13, flag(4));
+    file
+}
+
+unsafe fn build_subprogram(
+    dib: LLVMDIBuilderRef,
+    file: LLVMMetadataRef,
+    llfunc: LLVMValueRef,
+    func: &FuncDecl,
+    line: u32,
+) -> LLVMMetadataRef {
+    let flags = LLVMDIFlags_LLVMDIFlagZero;
+    let ty = LLVMDIBuilderCreateSubroutineType(dib, file, ptr::null_mut(), 0, flags);
+    let mut len = 0;
+    let link_name = LLVMGetValueName2(llfunc, &mut len);
+    let name = func.name.deref();
+    let local = (func.linkage == Linkage::Internal) as LLVMBool;
+    let sp = LLVMDIBuilderCreateFunction(
+        dib,
+        file,
+        name.as_ptr() as *const i8,
+        name.len(),
+        link_name,
+        len,
+        file,
+        line,
+        ty,
+        local,
+        1,
+        line,
+        flags,
+        0,
+    );
+    LLVMSetSubprogram(llfunc, sp);
+    sp
+}
+
+/// Whether `e` is a constant whose bytes are all zero, so an array of it can
+/// be memset.
+fn is_zero_const(e: &Expr) -> bool {
+    match &e.kind {
+        ExprKind::Integer(s) => {
+            let (s, radix) = int_radix(s);
+            u64::from_str_radix(s.trim_start_matches('-'), radix as u32) == Ok(0)
+        }
+        // -0.0 has its sign bit set.
+        ExprKind::Float(s) => !s.starts_with('-') && s.replace('_', "").parse() == Ok(0.0),
+        ExprKind::Bool(b) => !b,
+        ExprKind::Char(c) => *c == 0,
+        ExprKind::Null => true,
+        _ => false,
+    }
+}
+
+/// Stores `v` to `dst`. Comparison results are `i1`, so they are zero
+/// extended if the destination's storage type is wider. Any other mismatch
+/// is a bug.
+unsafe fn store_scalar(b: LLVMBuilderRef, v: LLVMValueRef, dst: LLVMValueRef) -> LLVMValueRef {
+    let src_ty = LLVMTypeOf(v);
+    let dst_ty = LLVMGetElementType(LLVMTypeOf(dst));
+    let v = if src_ty != dst_ty {
+        let is_int = |ty| LLVMGetTypeKind(ty) == LLVMTypeKind_LLVMIntegerTypeKind;
+        assert!(
+            src_ty == LLVMInt1Type() && is_int(dst_ty),
+            "only i1 comparison results are widened when stored"
+        );
+        LLVMBuildZExt(b, v, dst_ty, cstr!(""))
+    } else {
+        v
+    };
+    LLVMBuildStore(b, v, dst)
+}
+
+/// Attributes the instructions built next to `line`, if the function has
+/// debug info.
+unsafe fn set_debug_line(b: LLVMBuilderRef, scope: LLVMMetadataRef, line: u32) {
+    let loc = match scope.is_null() {
+        true => ptr::null_mut(),
+        false => LLVMDIBuilderCreateDebugLocation(
+            LLVMGetGlobalContext(),
+            line,
+            0,
+            scope,
+            ptr::null_mut(),
+        ),
+    };
+    LLVMSetCurrentDebugLocation2(b, loc);
+}
+
+/// Disposes the target machine when dropped.
+pub struct TargetMachine(pub LLVMTargetMachineRef);
+
+impl Drop for TargetMachine {
+    fn drop(&mut self) {
+        unsafe { LLVMDisposeTargetMachine(self.0) }
+    }
+}
+
+/// Disposes the module when dropped.
+pub struct Module(pub LLVMModuleRef);
+
+impl Drop for Module {
+    fn drop(&mut self) {
+        unsafe { LLVMDisposeModule(self.0) }
     }
+}
+
+/// Disposes the IR builder when dropped.
+struct Builder(LLVMBuilderRef);
+
+impl Drop for Builder {
+    fn drop(&mut self) {
+        unsafe { LLVMDisposeBuilder(self.0) }
+    }
+}
+
+/// Disposes the data layout when dropped.
+struct TargetData(LLVMTargetDataRef);
+
+impl Drop for TargetData {
+    fn drop(&mut self) {
+        unsafe { LLVMDisposeTargetData(self.0) }
+    }
+}
+
+/// Disposes the debug info builder when dropped.
+struct DIBuilder(LLVMDIBuilderRef);
+
+impl Drop for DIBuilder {
+    fn drop(&mut self) {
+        unsafe { LLVMDisposeDIBuilder(self.0) }
+    }
+}
+
+pub unsafe fn data_layout_string(layout: LLVMTargetDataRef) -> std::string::String {
+    let s = LLVMCopyStringRepOfTargetData(layout);
+    let string = CStr::from_ptr(s).to_string_lossy().into_owned();
+    LLVMDisposeMessage(s);
+    string
+}
+
+pub unsafe fn optimize(llmodule: LLVMModuleRef, level: OptLevel) {
+    let passes = match level {
+        OptLevel::None => cstr!("default<O0>"),
+        OptLevel::Less => cstr!("default<O1>"),
+        OptLevel::Default => cstr!("default<O2>"),
+        OptLevel::Aggressive => cstr!("default<O3>"),
+    };
+    let options = LLVMCreatePassBuilderOptions();
+    let err = LLVMRunPasses(llmodule, passes, ptr::null_mut(), options);
+    LLVMDisposePassBuilderOptions(options);
+    if !err.is_null() {
+        let msg = LLVMGetErrorMessage(err);
+        let s = format!("error running passes: {:?}", CStr::from_ptr(msg));
+        LLVMDisposeErrorMessage(msg);
+        error(s);
+    }
+}
+
+#[derive(Debug)]
+pub struct VerifyError {
+    // None for issues outside of any function body.
+    pub function: Option<std::string::String>,
+    pub message: std::string::String,
+}
+
+/// The verifier reports a single message for the whole module, so each
+/// function is verified in a copy of the module where every other function
+/// is reduced to a declaration. That attributes each issue to its function.
+pub unsafe fn verify_errors(llmodule: LLVMModuleRef) -> Vec<VerifyError> {
+    if verify(llmodule).is_ok() {
+        return vec![];
+    }
+    // Issues with every body removed (e.g. in signatures) aren't
+    // attributed to a function.
+    let mut errors = vec![];
+    for message in verify_bodies(llmodule, None) {
+        errors.push(VerifyError {
+            function: None,
+            message,
+        });
+    }
+    let mut llfunc = LLVMGetFirstFunction(llmodule);
+    while !llfunc.is_null() {
+        if LLVMIsDeclaration(llfunc) == 0 {
+            let name = CStr::from_ptr(LLVMGetValueName(llfunc));
+            let name = name.to_string_lossy().into_owned();
+            for message in verify_bodies(llmodule, Some(&name)) {
+                if errors
+                    .iter()
+                    .any(|e| e.function.is_none() && e.message == message)
+                {
+                    continue;
+                }
+                let function = Some(name.clone());
+                errors.push(VerifyError { function, message });
+            }
+        }
+        llfunc = LLVMGetNextFunction(llfunc);
+    }
+    errors
+}
 
-    (machine, llmodule)
+/// Verifies a copy of the module with every body except `keep`'s removed.
+unsafe fn verify_bodies(llmodule: LLVMModuleRef, keep: Option<&str>) -> Vec<std::string::String> {
+    let copy = LLVMCloneModule(llmodule);
+    let mut llfunc = LLVMGetFirstFunction(copy);
+    while !llfunc.is_null() {
+        let name = CStr::from_ptr(LLVMGetValueName(llfunc)).to_bytes();
+        if keep.map(str::as_bytes) != Some(name) && LLVMIsDeclaration(llfunc) == 0 {
+            while !LLVMGetFirstBasicBlock(llfunc).is_null() {
+                LLVMDeleteBasicBlock(LLVMGetFirstBasicBlock(llfunc));
+            }
+            LLVMSetComdat(llfunc, ptr::null_mut());
+            LLVMSetLinkage(llfunc, LLVMLinkage_LLVMExternalLinkage);
+        }
+        llfunc = LLVMGetNextFunction(llfunc);
+    }
+    let text = verify(copy).err();
+    LLVMDisposeModule(copy);
+
+    // Each issue is a line ending in '!' followed by the offending values.
+    let mut messages = vec![];
+    let mut message = std::string::String::new();
+    for line in text.unwrap_or_default().lines() {
+        if !line.starts_with(' ') && line.ends_with('!') && message != "" {
+            messages.push(std::mem::replace(&mut message, std::string::String::new()));
+        }
+        if message != "" {
+            message.push('\n');
+        }
+        message.push_str(line);
+    }
+    if message != "" {
+        messages.push(message);
+    }
+    messages
 }
 
-pub unsafe fn verify(llmodule: LLVMModuleRef) {
+pub unsafe fn verify(llmodule: LLVMModuleRef) -> Result<(), std::string::String> {
     let mut msg = ptr::null_mut();
-    LLVMVerifyModule(
+    let broken = LLVMVerifyModule(
         llmodule,
-        LLVMVerifierFailureAction_LLVMAbortProcessAction,
+        LLVMVerifierFailureAction_LLVMReturnStatusAction,
         &mut msg,
     );
+    let mut text = std::string::String::new();
+    if !msg.is_null() {
+        text = CStr::from_ptr(msg).to_string_lossy().into_owned();
+        LLVMDisposeMessage(msg);
+    }
+    match broken {
+        0 => Ok(()),
+        _ => Err(text),
+    }
+}
+
+/// Like `verify`, but with each issue attributed to its function, one per
+/// line.
+pub unsafe fn verify_report(llmodule: LLVMModuleRef) -> Result<(), std::string::String> {
+    let msg = match verify(llmodule) {
+        Ok(()) => return Ok(()),
+        Err(msg) => msg,
+    };
+    let errors = verify_errors(llmodule);
+    if errors.is_empty() {
+        return Err(format!("llvm verify error: {}", msg));
+    }
+    let mut lines = vec![];
+    for e in &errors {
+        match &e.function {
+            Some(name) => lines.push(format!("llvm verify error in {}: {}", name, e.message)),
+            None => lines.push(format!("llvm verify error: {}", e.message)),
+        }
+    }
+    Err(lines.join("\n"))
 }
 
-pub unsafe fn emit_object(machine: LLVMTargetMachineRef, llmodule: LLVMModuleRef) {
+pub unsafe fn emit_object(
+    machine: LLVMTargetMachineRef,
+    llmodule: LLVMModuleRef,
+    path: &str,
+) -> Result<(), std::string::String> {
     let mut msg = ptr::null_mut();
+    let mut path = path.to_string();
+    path.push('\0');
     if LLVMTargetMachineEmitToFile(
         machine,
         llmodule,
-        cstr!("a.o") as *mut i8,
+        path.as_ptr() as *mut i8,
         LLVMCodeGenFileType_LLVMObjectFile,
         &mut msg,
     ) != 0
     {
-        let msg = CStr::from_ptr(msg);
-        println!("error emitting object file: {:?}", msg);
-        error();
+        let text = format!("error emitting object file: {:?}", CStr::from_ptr(msg));
+        LLVMDisposeMessage(msg);
+        return Err(text);
     }
+    Ok(())
+}
+
+/// Verifies a built module and optimizes it at `options.opt_level`, so it's
+/// ready to emit.
+pub unsafe fn finish(llmodule: LLVMModuleRef, options: &Options) {
+    if let Err(msg) = verify_report(llmodule) {
+        error(msg);
+    }
+    if options.opt_level != OptLevel::None {
+        optimize(llmodule, options.opt_level);
+    }
+}
+
+pub unsafe fn emit_object_to_memory(
+    machine: LLVMTargetMachineRef,
+    llmodule: LLVMModuleRef,
+) -> Vec<u8> {
+    let mut msg = ptr::null_mut();
+    let mut buf = ptr::null_mut();
+    if LLVMTargetMachineEmitToMemoryBuffer(
+        machine,
+        llmodule,
+        LLVMCodeGenFileType_LLVMObjectFile,
+        &mut msg,
+        &mut buf,
+    ) != 0
+    {
+        let s = format!("error emitting object file: {:?}", CStr::from_ptr(msg));
+        LLVMDisposeMessage(msg);
+        error(s);
+    }
+    let start = LLVMGetBufferStart(buf) as *const u8;
+    let size = LLVMGetBufferSize(buf);
+    let bytes = std::slice::from_raw_parts(start, size).to_vec();
+    LLVMDisposeMemoryBuffer(buf);
+    bytes
+}
+
+pub unsafe fn emit_assembly(machine: LLVMTargetMachineRef, llmodule: LLVMModuleRef, path: &str) {
+    let mut msg = ptr::null_mut();
+    let mut path = path.to_string();
+    path.push('\0');
+    if LLVMTargetMachineEmitToFile(
+        machine,
+        llmodule,
+        path.as_ptr() as *mut i8,
+        LLVMCodeGenFileType_LLVMAssemblyFile,
+        &mut msg,
+    ) != 0
+    {
+        let s = format!("error emitting assembly file: {:?}", CStr::from_ptr(msg));
+        LLVMDisposeMessage(msg);
+        error(s);
+    }
+}
+
+/// The module's textual IR, as `emit_ir` writes it. Unlike LLVMDumpModule
+/// this doesn't number the params of declarations.
+pub unsafe fn ir_string(llmodule: LLVMModuleRef) -> std::string::String {
+    let s = LLVMPrintModuleToString(llmodule);
+    let string = CStr::from_ptr(s).to_string_lossy().into_owned();
+    LLVMDisposeMessage(s);
+    string
+}
+
+pub unsafe fn emit_ir(_machine: LLVMTargetMachineRef, llmodule: LLVMModuleRef, path: &str) {
+    let mut msg = ptr::null_mut();
+    let mut path = path.to_string();
+    path.push('\0');
+    if LLVMPrintModuleToFile(llmodule, path.as_ptr() as *const i8, &mut msg) != 0 {
+        let s = format!("error emitting llvm ir: {:?}", CStr::from_ptr(msg));
+        LLVMDisposeMessage(msg);
+        error(s);
+    }
+}
+
+/// Compiles the module in memory and calls `entry`, which must take no
+/// arguments and return i32. The execution engine takes over the module.
+pub unsafe fn jit_run(llmodule: Module, entry: &str) -> i32 {
+    LLVMLinkInMCJIT();
+    LLVMInitializeNativeTarget();
+    LLVMInitializeNativeAsmPrinter();
+    LLVMInitializeNativeAsmParser();
+    let mut engine = ptr::null_mut();
+    let mut msg = ptr::null_mut();
+    if LLVMCreateExecutionEngineForModule(&mut engine, llmodule.0, &mut msg) != 0 {
+        let s = format!("error creating execution engine: {:?}", CStr::from_ptr(msg));
+        LLVMDisposeMessage(msg);
+        error(s);
+    }
+    std::mem::forget(llmodule);
+    let mut name = entry.to_string();
+    name.push('\0');
+    let addr = LLVMGetFunctionAddress(engine, name.as_ptr() as *const i8);
+    if addr == 0 {
+        LLVMDisposeExecutionEngine(engine);
+        error(format!("missing entry function {:?}", entry));
+    }
+    let f: extern "C" fn() -> i32 = std::mem::transmute(addr as usize);
+    let code = f();
+    LLVMDisposeExecutionEngine(engine);
+    code
 }
 
 unsafe fn build_consts(types: &TypeBuilder, consts: &[Const]) -> Vec<LLVMValueRef> {
@@ -101,7 +819,7 @@ unsafe fn build_consts(types: &TypeBuilder, consts: &[Const]) -> Vec<LLVMValueRe
         types: types,
     };
     for (id, c) in consts.iter().enumerate() {
-        let v = b.build(c);
+        let v = b.build(&c.expr);
         b.consts[id] = Some(v);
     }
     let mut consts = vec![];
@@ -111,21 +829,108 @@ unsafe fn build_consts(types: &TypeBuilder, consts: &[Const]) -> Vec<LLVMValueRe
     consts
 }
 
+unsafe fn build_globals(
+    llmodule: LLVMModuleRef,
+    types: &TypeBuilder,
+    globals: &[Global],
+    options: &Options,
+) -> Vec<LLVMValueRef> {
+    let mut b = ConstBuilder {
+        consts: vec![],
+        types: types,
+    };
+    let mut llglobals = vec![];
+    for g in globals {
+        let mut name = g.name.to_string();
+        name.push('\0');
+        let lltype = types.lltype(g.ty);
+        let llglobal = LLVMAddGlobal(llmodule, lltype, name.as_ptr() as *const i8);
+        // Without an initializer it's an external declaration.
+        if let Some(init) = &g.init {
+            LLVMSetInitializer(llglobal, b.build(init));
+        }
+        set_linkage(llglobal, g.linkage, g.visibility, g.init.is_some(), options);
+        llglobals.push(llglobal);
+    }
+    llglobals
+}
+
+/// Finds the innermost loop destination, or the one for the named loop.
+fn loop_dest(
+    dests: &[(Option<crate::String>, LLVMBasicBlockRef)],
+    label: Option<crate::String>,
+) -> Option<LLVMBasicBlockRef> {
+    match label {
+        Some(label) => dests
+            .iter()
+            .rev()
+            .find(|&&(l, _)| l == Some(label))
+            .map(|&(_, b)| b),
+        None => dests.last().map(|&(_, b)| b),
+    }
+}
+
+fn defines_label(stmt: &Stmt) -> bool {
+    let block_defines_label = |block: &Block| block.stmts.iter().any(defines_label);
+    match stmt {
+        Stmt::Label(_) => true,
+        Stmt::If(_, body, None) | Stmt::While(_, _, body) | Stmt::For(_, _, _, _, body) => {
+            block_defines_label(body)
+        }
+        Stmt::If(_, body, Some(else_body)) => {
+            block_defines_label(body) || block_defines_label(else_body)
+        }
+        Stmt::Match(_, arms, default) => {
+            arms.iter().any(|(_, body)| block_defines_label(body))
+                || default.as_ref().map_or(false, block_defines_label)
+        }
+        _ => false,
+    }
+}
+
 struct ConstBuilder<'a> {
     consts: Vec<Option<LLVMValueRef>>,
     types: &'a TypeBuilder<'a>,
 }
 
 impl<'a> ConstBuilder<'a> {
-    unsafe fn build(&mut self, c: &Const) -> LLVMValueRef {
-        match &c.expr.kind {
+    unsafe fn build(&mut self, e: &Expr) -> LLVMValueRef {
+        match &e.kind {
             ExprKind::Integer(s) => {
-                let lltype = self.types.lltype(c.expr.ty);
+                let lltype = self.types.lltype(e.ty);
+                let (s, radix) = int_radix(s);
                 let ptr = s.as_ptr() as *const i8;
                 let len = s.len() as u32;
-                let radix = 10;
                 LLVMConstIntOfStringAndSize(lltype, ptr, len, radix)
             }
+            ExprKind::Float(s) => {
+                let lltype = self.types.lltype(e.ty);
+                let s = s.replace('_', "");
+                let ptr = s.as_ptr() as *const i8;
+                let len = s.len() as u32;
+                LLVMConstRealOfStringAndSize(lltype, ptr, len)
+            }
+            &ExprKind::Bool(b) => LLVMConstInt(LLVMInt1Type(), b as u64, 0),
+            &ExprKind::Char(ch) => {
+                let lltype = self.types.lltype(e.ty);
+                LLVMConstInt(lltype, ch as u64, 0)
+            }
+            &ExprKind::EnumVariant(i) => {
+                // Unit variant: zeroed body (if any) followed by the tag.
+                let lltype = self.types.lltype(e.ty);
+                let tag_index = self.types.enum_tag_index(e.ty);
+                let mut fields = vec![];
+                for j in 0..tag_index {
+                    let ty = LLVMStructGetTypeAtIndex(lltype, j);
+                    fields.push(LLVMConstNull(ty));
+                }
+                let tag_type = LLVMStructGetTypeAtIndex(lltype, tag_index);
+                let tag = self.types.variant_tag(e.ty, i);
+                fields.push(LLVMConstInt(tag_type, tag as u64, 0));
+                let p = fields.as_mut_ptr();
+                let n = fields.len() as u32;
+                LLVMConstNamedStruct(lltype, p, n)
+            }
             _ => panic!(),
         }
     }
@@ -134,33 +939,139 @@ impl<'a> ConstBuilder<'a> {
 struct TypeBuilder<'a> {
     layout: LLVMTargetDataRef,
     lltypes: Vec<LLVMTypeRef>,
+    fields: Vec<Vec<FieldLayout>>,
+    // Body struct of each enum variant.
+    variants: Vec<Vec<LLVMTypeRef>>,
+    // Field types of each union.
+    members: Vec<Vec<LLVMTypeRef>>,
     types: &'a [Type],
+    // Pass small aggregates in registers, per the System V x86-64 ABI.
+    sysv: bool,
+    // Aggregates always passed in memory, see `new`.
+    indirect: Vec<bool>,
+}
+
+/// What an eightbyte of an aggregate holds, for System V classification.
+#[derive(Debug, Copy, Clone, Default)]
+struct Eightbyte {
+    // Any integer or pointer makes it go in a general purpose register.
+    int: bool,
+    double: bool,
+    // Offset just past the last byte of data in it.
+    end: u64,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct FieldLayout {
+    // Index of the LLVM struct element holding the field.
+    index: u32,
+    // Bit offset and width within the element, for bit-fields.
+    bits: Option<(u32, u32)>,
 }
 
 impl<'a> TypeBuilder<'a> {
-    unsafe fn new(layout: LLVMTargetDataRef, types: &'a [Type]) -> Self {
+    unsafe fn new(layout: LLVMTargetDataRef, types: &'a [Type], sysv: bool) -> Self {
         let mut b = TypeBuilder {
             layout: layout,
-            lltypes: vec![],
+            lltypes: vec![ptr::null_mut(); types.len()],
+            fields: vec![vec![]; types.len()],
+            variants: vec![vec![]; types.len()],
+            members: vec![vec![]; types.len()],
             types: types,
+            sysv: sysv,
+            indirect: vec![false; types.len()],
         };
-        for type_id in 0..types.len() {
-            let lltype = b.build_type(type_id);
-            b.lltypes.push(lltype);
-        }
+        // Forward declare the named types first, so that pointers and arrays
+        // refer to them no matter which order they were interned in.
+        let mut has_body = vec![true; types.len()];
         for (id, ty) in types.iter().enumerate() {
-            if let Type::Struct(sty) = ty {
-                b.set_struct_body(id, sty);
+            if let Type::Struct(_) | Type::Union(_) | Type::Enum(_) = ty {
+                b.lltypes[id] = b.build_type(id);
+                has_body[id] = false;
+            }
+        }
+        // How a function passes its aggregates depends on their layout, so
+        // function types wait for those bodies, while bodies wait for their
+        // element types.
+        loop {
+            let mut progress = false;
+            for (id, ty) in types.iter().enumerate() {
+                if b.lltypes[id].is_null() && b.deps_ready(id) {
+                    b.lltypes[id] = b.build_type(id);
+                    progress = true;
+                }
+                if !has_body[id] && b.body_ready(id) {
+                    match ty {
+                        Type::Struct(sty) => b.fields[id] = b.set_struct_body(id, sty),
+                        Type::Union(uty) => b.members[id] = b.set_union_body(id, uty),
+                        Type::Enum(ety) => b.variants[id] = b.set_enum_body(id, ety),
+                        _ => panic!(),
+                    }
+                    has_body[id] = true;
+                    progress = true;
+                }
+            }
+            if progress {
+                continue;
             }
-            if let Type::Enum(ety) = ty {
-                b.set_enum_body(id, ety);
+            // Left with a cycle, like a struct holding a pointer to a function
+            // taking that struct. Break it by passing the struct in memory.
+            let stuck = (0..types.len()).find(|&id| match &types[id] {
+                Type::Func(func) => {
+                    let mut tys = func.params.iter().chain(Some(&func.ret));
+                    b.lltypes[id].is_null() && tys.all(|&ty| !b.lltype(ty).is_null())
+                }
+                _ => false,
+            });
+            let id = match stuck {
+                Some(id) => id,
+                None => break,
+            };
+            if let Type::Func(func) = &types[id] {
+                for &ty in func.params.iter().chain(Some(&func.ret)) {
+                    let aggregate = b.irtype(ty).kind() == TypeKind::Aggregate;
+                    if aggregate && LLVMTypeIsSized(b.lltype(ty)) == 0 {
+                        b.indirect[ty] = true;
+                    }
+                }
             }
+            b.lltypes[id] = b.build_type(id);
         }
         b
     }
 
+    fn deps_ready(&self, ty: TypeId) -> bool {
+        let built = |ty: TypeId| !self.lltype(ty).is_null();
+        match self.irtype(ty) {
+            Type::Pointer(ty) | Type::Array(ty, _) => built(*ty),
+            Type::Tuple(tys) => tys.iter().all(|&ty| built(ty)),
+            Type::Func(func) => func.params.iter().chain(Some(&func.ret)).all(|&ty| {
+                let sized = || unsafe { LLVMTypeIsSized(self.lltype(ty)) != 0 };
+                built(ty) && (self.irtype(ty).kind() != TypeKind::Aggregate || sized())
+            }),
+            _ => true,
+        }
+    }
+
+    unsafe fn body_ready(&self, ty: TypeId) -> bool {
+        match self.irtype(ty) {
+            Type::Struct(sty) => sty.fields.iter().all(|f| !self.lltype(f.1).is_null()),
+            // Enum and union bodies are sized after the largest variant or field.
+            Type::Enum(ety) => ety.variants.iter().flat_map(|v| &v.args).all(|&ty| {
+                let lltype = self.lltype(ty);
+                !lltype.is_null() && LLVMTypeIsSized(lltype) != 0
+            }),
+            Type::Union(uty) => uty.fields.iter().all(|&(_, ty)| {
+                let lltype = self.lltype(ty);
+                !lltype.is_null() && LLVMTypeIsSized(lltype) != 0
+            }),
+            _ => false,
+        }
+    }
+
     unsafe fn build_type(&self, ty: TypeId) -> LLVMTypeRef {
-        if let Some(&lltype) = self.lltypes.get(ty) {
+        let lltype = self.lltypes[ty];
+        if !lltype.is_null() {
             return lltype;
         }
         match self.irtype(ty) {
@@ -169,6 +1080,12 @@ impl<'a> TypeBuilder<'a> {
             Type::I16 => LLVMInt16Type(),
             Type::I32 => LLVMInt32Type(),
             Type::I64 => LLVMInt64Type(),
+            Type::I128 => LLVMIntType(128),
+            Type::U8 => LLVMInt8Type(),
+            Type::U16 => LLVMInt16Type(),
+            Type::U32 => LLVMInt32Type(),
+            Type::U64 => LLVMInt64Type(),
+            Type::U128 => LLVMIntType(128),
             Type::F32 => LLVMFloatType(),
             Type::F64 => LLVMDoubleType(),
             Type::Pointer(ty) => {
@@ -200,25 +1117,72 @@ impl<'a> TypeBuilder<'a> {
                 let name = name.as_ptr() as *const i8;
                 LLVMStructCreateNamed(LLVMGetGlobalContext(), name)
             }
+            Type::Union(uty) => {
+                let mut name = uty.name.to_string();
+                name.push('\0');
+                let name = name.as_ptr() as *const i8;
+                LLVMStructCreateNamed(LLVMGetGlobalContext(), name)
+            }
         }
     }
 
-    unsafe fn set_struct_body(&self, id: TypeId, sty: &StructType) {
+    /// Consecutive bit-fields of the same type are packed into one element.
+    unsafe fn set_struct_body(&self, id: TypeId, sty: &StructType) -> Vec<FieldLayout> {
         let lltype = self.lltype(id);
         let mut elem_types = vec![];
-        for &(_, ty) in &sty.fields {
+        let mut fields = vec![];
+        // Element type and bits used of the last bit-field element.
+        let mut unit: Option<(LLVMTypeRef, u32)> = None;
+        for &(_, ty, bits) in &sty.fields {
             let ty = self.lltype(ty);
-            elem_types.push(ty);
+            let width = match bits {
+                Some(width) => width,
+                None => {
+                    unit = None;
+                    elem_types.push(ty);
+                    let index = elem_types.len() as u32 - 1;
+                    fields.push(FieldLayout { index, bits: None });
+                    continue;
+                }
+            };
+            let offset = match unit {
+                Some((unit_ty, used))
+                    if unit_ty == ty && used + width <= LLVMGetIntTypeWidth(ty) =>
+                {
+                    used
+                }
+                _ => {
+                    elem_types.push(ty);
+                    0
+                }
+            };
+            unit = Some((ty, offset + width));
+            let index = elem_types.len() as u32 - 1;
+            let bits = Some((offset, width));
+            fields.push(FieldLayout { index, bits });
+        }
+        let p = elem_types.as_mut_ptr();
+        let n = elem_types.len() as u32;
+        LLVMStructSetBody(lltype, p, n, sty.packed as LLVMBool);
+        fields
+    }
+
+    fn field(&self, ty: TypeId, i: u32) -> FieldLayout {
+        match self.fields[ty].get(i as usize) {
+            Some(&field) => field,
+            None => FieldLayout {
+                index: i,
+                bits: None,
+            },
         }
-        LLVMStructSetBody(lltype, elem_types.as_mut_ptr(), elem_types.len() as u32, 0);
     }
 
-    unsafe fn set_enum_body(&self, id: TypeId, ety: &EnumType) {
+    unsafe fn set_enum_body(&self, id: TypeId, ety: &EnumType) -> Vec<LLVMTypeRef> {
         let enum_struct = self.lltype(id);
-        let tag_type = LLVMInt8Type();
+        let tag_type = LLVMIntType(ety.tag_bits());
 
         // Create struct types for each variant.
-        let mut largest: Option<(u64, LLVMTypeRef)> = None;
+        let mut variants = vec![];
         for variant in &ety.variants {
             let mut args = vec![];
             for &arg in &variant.args {
@@ -227,21 +1191,78 @@ impl<'a> TypeBuilder<'a> {
             }
             let p = args.as_mut_ptr();
             let n = args.len() as u32;
-            let ty = LLVMStructType(p, n, 0);
-            let size = LLVMStoreSizeOfType(self.layout, ty);
-
-            largest = match largest {
-                Some((n, other)) if n >= size => Some((n, other)),
-                _ => Some((size, ty)),
-            };
+            variants.push(LLVMStructType(p, n, 0));
         }
-        let mut fields = match largest {
-            Some((_, ty)) => vec![ty, tag_type],
-            _ => vec![tag_type],
-        };
+        let mut fields = self.overlay(&variants);
+        fields.push(tag_type);
         let p = fields.as_mut_ptr();
         let n = fields.len() as u32;
         LLVMStructSetBody(enum_struct, p, n, 0);
+        variants
+    }
+
+    unsafe fn set_union_body(&self, id: TypeId, uty: &UnionType) -> Vec<LLVMTypeRef> {
+        let members: Vec<_> = uty.fields.iter().map(|&(_, ty)| self.lltype(ty)).collect();
+        let mut fields = self.overlay(&members);
+        let p = fields.as_mut_ptr();
+        let n = fields.len() as u32;
+        LLVMStructSetBody(self.lltype(id), p, n, 0);
+        members
+    }
+
+    /// Elements of a struct that can hold any of `tys` at offset 0, like a C
+    /// union: the most aligned type, padded out to the size of the largest.
+    unsafe fn overlay(&self, tys: &[LLVMTypeRef]) -> Vec<LLVMTypeRef> {
+        let align = |ty| LLVMABIAlignmentOfType(self.layout, ty);
+        let size = |ty| LLVMABISizeOfType(self.layout, ty);
+        let mut aligned: Option<LLVMTypeRef> = None;
+        for &ty in tys {
+            aligned = match aligned {
+                Some(other) if align(other) >= align(ty) => Some(other),
+                _ => Some(ty),
+            };
+        }
+        let aligned = match aligned {
+            Some(ty) => ty,
+            None => return vec![],
+        };
+        let largest = tys.iter().map(|&ty| size(ty)).max().unwrap();
+        let mut elems = vec![aligned];
+        if largest > size(aligned) {
+            let pad = largest - size(aligned);
+            elems.push(LLVMArrayType(LLVMInt8Type(), pad as u32));
+        }
+        elems
+    }
+
+    /// The field types of `lltype` if it's a union.
+    fn union_members(&self, lltype: LLVMTypeRef) -> Option<&[LLVMTypeRef]> {
+        let id = self.lltypes.iter().position(|&ty| ty == lltype)?;
+        match self.irtype(id) {
+            Type::Union(_) => Some(&self.members[id]),
+            _ => None,
+        }
+    }
+
+    fn variant_tag(&self, ty: TypeId, variant: u32) -> u32 {
+        match self.irtype(ty) {
+            Type::Enum(ety) => ety.variants[variant as usize].tag,
+            _ => panic!(),
+        }
+    }
+
+    fn variant_type(&self, ty: TypeId, variant: u32) -> LLVMTypeRef {
+        self.variants[ty][variant as usize]
+    }
+
+    /// The tag is the last element of the enum struct: `[body, tag]` if any variant has a
+    /// body, otherwise just `[tag]`.
+    unsafe fn enum_tag_index(&self, ty: TypeId) -> u32 {
+        LLVMCountStructElementTypes(self.lltype(ty)) - 1
+    }
+
+    unsafe fn enum_tag_type(&self, ty: TypeId) -> LLVMTypeRef {
+        LLVMStructGetTypeAtIndex(self.lltype(ty), self.enum_tag_index(ty))
     }
 
     fn irtype(&self, ty: TypeId) -> &'a Type {
@@ -253,51 +1274,238 @@ impl<'a> TypeBuilder<'a> {
     }
 
     unsafe fn func_type(&self, func: &FuncType) -> LLVMTypeRef {
+        self.func_sig(func).0
+    }
+
+    unsafe fn func_sig(&self, func: &FuncType) -> (LLVMTypeRef, ParamLayout) {
         let mut params = vec![];
+        let mut layout = ParamLayout {
+            params: vec![],
+            sret: None,
+            ret: None,
+            attrs: vec![],
+        };
+        // General purpose and SSE registers left for arguments.
+        let mut int_regs = 6;
+        let mut sse_regs = 8;
+        let ret = match self.irtype(func.ret).kind() {
+            TypeKind::Aggregate => match self.classify(func.ret) {
+                Some(mut regs) => {
+                    let ret = match regs.len() {
+                        0 => LLVMVoidType(),
+                        1 => regs[0],
+                        n => LLVMStructType(regs.as_mut_ptr(), n as u32, 0),
+                    };
+                    layout.ret = Some(regs);
+                    ret
+                }
+                None => {
+                    let ret = self.lltype(func.ret);
+                    // LLVM wants the return slot first, where C callers pass it.
+                    layout.sret = Some(0);
+                    layout.attrs.push((1, type_attr("sret", ret)));
+                    params.push(LLVMPointerType(ret, 0));
+                    int_regs -= 1;
+                    LLVMVoidType()
+                }
+            },
+            TypeKind::Unit => LLVMVoidType(),
+            TypeKind::Scalar => self.lltype(func.ret),
+        };
         for &ty in &func.params {
-            let ty = match self.irtype(ty).kind() {
+            let i = params.len() as u32;
+            match self.irtype(ty).kind() {
                 TypeKind::Aggregate => {
-                    let sty = self.lltype(ty);
-                    LLVMPointerType(sty, 0)
+                    let regs = self.classify(ty).filter(|regs| {
+                        let ints = regs.iter().filter(|&&reg| is_int_reg(reg)).count();
+                        ints <= int_regs && regs.len() - ints <= sse_regs
+                    });
+                    match regs {
+                        Some(regs) => {
+                            let ints = regs.iter().filter(|&&reg| is_int_reg(reg)).count();
+                            int_regs -= ints;
+                            sse_regs -= regs.len() - ints;
+                            params.extend_from_slice(&regs);
+                            layout.params.push(ParamAbi::Split(i, regs));
+                        }
+                        None => {
+                            // The callee gets its own copy, as a C caller would pass it.
+                            let sty = self.lltype(ty);
+                            layout.attrs.push((i + 1, type_attr("byval", sty)));
+                            params.push(LLVMPointerType(sty, 0));
+                            layout.params.push(ParamAbi::Direct(i));
+                        }
+                    }
                 }
-                TypeKind::Unit => continue,
-                TypeKind::Scalar => self.lltype(ty),
+                TypeKind::Unit => layout.params.push(ParamAbi::None),
+                TypeKind::Scalar => {
+                    let lltype = self.lltype(ty);
+                    match LLVMGetTypeKind(lltype) {
+                        LLVMTypeKind_LLVMFloatTypeKind | LLVMTypeKind_LLVMDoubleTypeKind => {
+                            sse_regs = sse_regs.saturating_sub(1)
+                        }
+                        _ => int_regs = int_regs.saturating_sub(1),
+                    }
+                    params.push(lltype);
+                    layout.params.push(ParamAbi::Direct(i));
+                }
+            }
+        }
+        let var_args = if func.var_args { 1 } else { 0 };
+
+        let lltype = LLVMFunctionType(ret, params.as_mut_ptr(), params.len() as u32, var_args);
+        (lltype, layout)
+    }
+
+    /// The registers that a System V x86-64 caller passes an aggregate in,
+    /// one per eightbyte, or None if it's passed in memory.
+    unsafe fn classify(&self, ty: TypeId) -> Option<Vec<LLVMTypeRef>> {
+        if !self.sysv || self.indirect[ty] {
+            return None;
+        }
+        let lltype = self.lltype(ty);
+        let size = LLVMABISizeOfType(self.layout, lltype);
+        if size > 16 {
+            return None;
+        }
+        let mut eightbytes = vec![Eightbyte::default(); (size as usize + 7) / 8];
+        if !self.classify_at(lltype, 0, &mut eightbytes) {
+            return None;
+        }
+        let mut regs = vec![];
+        for (i, e) in eightbytes.iter().enumerate() {
+            let bytes = e.end - 8 * i as u64;
+            let reg = if e.int {
+                LLVMIntType(bytes as u32 * 8)
+            } else if e.double {
+                LLVMDoubleType()
+            } else if bytes > 4 {
+                LLVMVectorType(LLVMFloatType(), 2)
+            } else {
+                LLVMFloatType()
             };
-            params.push(ty);
+            regs.push(reg);
         }
+        Some(regs)
+    }
 
-        let ret = match self.irtype(func.ret).kind() {
-            TypeKind::Aggregate => {
-                let ret = self.lltype(func.ret);
-                let sret = LLVMPointerType(ret, 0);
-                params.push(sret);
-                LLVMVoidType()
+    /// Returns false if a field is misaligned, which sends the whole
+    /// aggregate to memory.
+    unsafe fn classify_at(
+        &self,
+        lltype: LLVMTypeRef,
+        offset: u64,
+        eightbytes: &mut [Eightbyte],
+    ) -> bool {
+        // A union's fields all start at its offset.
+        if let Some(members) = self.union_members(lltype) {
+            return members
+                .iter()
+                .all(|&ty| self.classify_at(ty, offset, eightbytes));
+        }
+        match LLVMGetTypeKind(lltype) {
+            LLVMTypeKind_LLVMStructTypeKind => (0..LLVMCountStructElementTypes(lltype)).all(|i| {
+                let elem = LLVMStructGetTypeAtIndex(lltype, i);
+                let offset = offset + LLVMOffsetOfElement(self.layout, lltype, i);
+                self.classify_at(elem, offset, eightbytes)
+            }),
+            LLVMTypeKind_LLVMArrayTypeKind => {
+                let elem = LLVMGetElementType(lltype);
+                let stride = LLVMABISizeOfType(self.layout, elem);
+                let n = LLVMGetArrayLength(lltype) as u64;
+                (0..n).all(|i| self.classify_at(elem, offset + i * stride, eightbytes))
             }
-            TypeKind::Unit => LLVMVoidType(),
-            TypeKind::Scalar => self.lltype(func.ret),
-        };
-        let var_args = if func.var_args { 1 } else { 0 };
+            kind => {
+                if offset % LLVMABIAlignmentOfType(self.layout, lltype) as u64 != 0 {
+                    return false;
+                }
+                let end = offset + LLVMStoreSizeOfType(self.layout, lltype);
+                // An i128 covers two eightbytes.
+                for i in offset / 8..(end + 7) / 8 {
+                    let e = &mut eightbytes[i as usize];
+                    e.end = e.end.max(end.min(8 * (i + 1)));
+                    match kind {
+                        LLVMTypeKind_LLVMFloatTypeKind => {}
+                        LLVMTypeKind_LLVMDoubleTypeKind => e.double = true,
+                        _ => e.int = true,
+                    }
+                }
+                true
+            }
+        }
+    }
+}
+
+unsafe fn is_int_reg(reg: LLVMTypeRef) -> bool {
+    LLVMGetTypeKind(reg) == LLVMTypeKind_LLVMIntegerTypeKind
+}
+
+/// Where each IR param ended up in the LLVM function's param list.
+struct ParamLayout {
+    params: Vec<ParamAbi>,
+    // Pointer to the caller's return slot for aggregate returns.
+    sret: Option<u32>,
+    // Registers an aggregate is returned in, instead of through sret.
+    ret: Option<Vec<LLVMTypeRef>>,
+    // Attributes to add at both the declaration and each call site.
+    attrs: Vec<(LLVMAttributeIndex, LLVMAttributeRef)>,
+}
 
-        LLVMFunctionType(ret, params.as_mut_ptr(), params.len() as u32, var_args)
+/// How an IR param is passed.
+enum ParamAbi {
+    // Unit params aren't passed.
+    None,
+    // A scalar, or a pointer to an aggregate.
+    Direct(u32),
+    // An aggregate passed in registers, one param per register from the index.
+    Split(u32, Vec<LLVMTypeRef>),
+}
+
+fn call_conv(cc: CallConv) -> LLVMCallConv {
+    match cc {
+        CallConv::C => LLVMCallConv_LLVMCCallConv,
+        CallConv::Fast => LLVMCallConv_LLVMFastCallConv,
+        CallConv::Cold => LLVMCallConv_LLVMColdCallConv,
     }
 }
 
+unsafe fn enum_attr(name: &str) -> LLVMAttributeRef {
+    let kind = LLVMGetEnumAttributeKindForName(name.as_ptr() as *const i8, name.len());
+    LLVMCreateEnumAttribute(LLVMGetGlobalContext(), kind, 0)
+}
+
+unsafe fn type_attr(name: &str, ty: LLVMTypeRef) -> LLVMAttributeRef {
+    let kind = LLVMGetEnumAttributeKindForName(name.as_ptr() as *const i8, name.len());
+    LLVMCreateTypeAttribute(LLVMGetGlobalContext(), kind, ty)
+}
+
 unsafe fn build_func_body(
     b: LLVMBuilderRef,
     type_bld: &TypeBuilder,
     llfuncs: &[LLVMValueRef],
     llconsts: &[LLVMValueRef],
+    llglobals: &[LLVMValueRef],
     func: &FuncDecl,
     body: &FuncBody,
+    scope: LLVMMetadataRef,
+    wrapping: bool,
+    null_checks: bool,
+    bounds_checks: bool,
 ) {
     let llfunc = llfuncs[body.id];
     let entry = LLVMAppendBasicBlock(llfunc, cstr!("entry"));
     LLVMPositionBuilderAtEnd(b, entry);
-
-    let sret = match type_bld.irtype(func.ty.ret).kind() {
-        TypeKind::Aggregate => Some(LLVMGetLastParam(llfunc)),
-        TypeKind::Unit => None,
-        TypeKind::Scalar => None,
+    set_debug_line(b, scope, body.line);
+
+    let (_, layout) = type_bld.func_sig(&func.ty);
+    let sret = match (layout.sret, &layout.ret) {
+        (Some(i), _) => Some(LLVMGetParam(llfunc, i)),
+        // Built in a local, then loaded into the return registers.
+        (None, Some(_)) => {
+            let lltype = type_bld.lltype(func.ty.ret);
+            Some(LLVMBuildAlloca(b, lltype, cstr!("")))
+        }
+        (None, None) => None,
     };
 
     let mut locals = vec![];
@@ -308,26 +1516,105 @@ unsafe fn build_func_body(
     }
     let locals = &locals;
 
+    let mut labels = vec![];
+    for _ in &body.labels {
+        labels.push(LLVMAppendBasicBlock(llfunc, cstr!("")));
+    }
+
     let mut b = StmtBuilder {
         bld: b,
         tybld: type_bld,
 
         llfuncs: llfuncs,
         llconsts: llconsts,
+        llglobals: llglobals,
         llfunc: llfunc,
         locals: locals,
+        params: vec![],
         sret: sret,
+        ret: layout.ret,
+        labels: labels,
 
         break_dest: vec![],
         continue_dest: vec![],
         block: entry,
+        scope: scope,
+        wrapping: wrapping,
+        null_checks: null_checks,
+        bounds_checks: bounds_checks,
+        tail_call: false,
+        tail_calls: vec![],
     };
+    for (abi, &ty) in layout.params.iter().zip(&func.ty.params) {
+        let param = match abi {
+            ParamAbi::None => None,
+            &ParamAbi::Direct(i) => Some(LLVMGetParam(llfunc, i)),
+            ParamAbi::Split(i, regs) => {
+                let p = LLVMBuildAlloca(b.bld, type_bld.lltype(ty), cstr!(""));
+                let n = regs.len() as u32;
+                let vs: Vec<_> = (*i..i + n).map(|i| LLVMGetParam(llfunc, i)).collect();
+                b.store_regs(ty, p, regs, &vs);
+                Some(p)
+            }
+        };
+        b.params.push(param);
+    }
     b.build_block(&body.body);
 
     let term = LLVMGetBasicBlockTerminator(b.block);
-    if term.is_null() {
+    if term.is_null()
+        && b.block != entry
+        && LLVMGetFirstUse(LLVMBasicBlockAsValue(b.block)).is_null()
+    {
+        // The block after a trailing goto.
+        LLVMBuildUnreachable(b.bld);
+    } else if term.is_null() {
         LLVMBuildRetVoid(b.bld);
     }
+
+    // A tail call can't access the caller's stack, so only mark them if
+    // nothing can point into it.
+    if !allocas_escape(llfunc) {
+        for &call in &b.tail_calls {
+            LLVMSetTailCall(call, 1);
+        }
+    }
+}
+
+/// Whether a pointer to one of the function's stack slots is used other than
+/// to directly load or store through it.
+unsafe fn allocas_escape(llfunc: LLVMValueRef) -> bool {
+    let mut block = LLVMGetFirstBasicBlock(llfunc);
+    while !block.is_null() {
+        let mut inst = LLVMGetFirstInstruction(block);
+        while !inst.is_null() {
+            if LLVMGetInstructionOpcode(inst) == LLVMOpcode_LLVMAlloca && escapes(inst) {
+                return true;
+            }
+            inst = LLVMGetNextInstruction(inst);
+        }
+        block = LLVMGetNextBasicBlock(block);
+    }
+    false
+}
+
+unsafe fn escapes(p: LLVMValueRef) -> bool {
+    let mut u = LLVMGetFirstUse(p);
+    while !u.is_null() {
+        let user = LLVMGetUser(u);
+        let ok = match LLVMGetInstructionOpcode(user) {
+            LLVMOpcode_LLVMLoad => true,
+            // Storing the pointer itself lets it escape.
+            LLVMOpcode_LLVMStore => LLVMGetOperand(user, 0) != p,
+            LLVMOpcode_LLVMGetElementPtr | LLVMOpcode_LLVMBitCast => !escapes(user),
+            _ => false,
+        };
+        if !ok {
+            return true;
+        }
+        u = LLVMGetNextUse(u);
+    }
+    false
 }
 
 struct StmtBuilder<'a> {
@@ -335,14 +1622,31 @@ struct StmtBuilder<'a> {
     tybld: &'a TypeBuilder<'a>,
     llfuncs: &'a [LLVMValueRef],
     llconsts: &'a [LLVMValueRef],
+    llglobals: &'a [LLVMValueRef],
 
     llfunc: LLVMValueRef,
     locals: &'a [LLVMValueRef],
+    // None for unit params, otherwise the value or aggregate pointer.
+    params: Vec<Option<LLVMValueRef>>,
     sret: Option<LLVMValueRef>,
+    ret: Option<Vec<LLVMTypeRef>>,
+    labels: Vec<LLVMBasicBlockRef>,
 
-    break_dest: Vec<LLVMBasicBlockRef>,
-    continue_dest: Vec<LLVMBasicBlockRef>,
+    break_dest: Vec<(Option<crate::String>, LLVMBasicBlockRef)>,
+    continue_dest: Vec<(Option<crate::String>, LLVMBasicBlockRef)>,
     block: LLVMBasicBlockRef,
+    // The function's debug info subprogram, or null.
+    scope: LLVMMetadataRef,
+    // Leave nsw/nuw off integer arithmetic.
+    wrapping: bool,
+    // Check pointers aren't null before dereferencing them.
+    null_checks: bool,
+    // Check array indices are in bounds.
+    bounds_checks: bool,
+    // Set by `return f(x)`, taken by the next call built.
+    tail_call: bool,
+    // Calls in tail position, marked tail once the body is built.
+    tail_calls: Vec<LLVMValueRef>,
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -352,10 +1656,114 @@ enum Value {
     Aggregate(LLVMValueRef),
 }
 
-impl<'a> StmtBuilder<'a> {
-    unsafe fn build_block(&mut self, block: &Block) {
-        for stmt in &block.stmts {
-            self.build_stmt(stmt);
+impl<'a> StmtBuilder<'a> {
+    unsafe fn build_block(&mut self, block: &Block) {
+        for (stmt, &line) in block.stmts.iter().zip(&block.lines) {
+            // Code after a return, break, or continue is dead, unless a label
+            // makes it reachable again.
+            if !self.is_open() {
+                match stmt {
+                    Stmt::Label(_) => {}
+                    _ if defines_label(stmt) => {
+                        let block = LLVMAppendBasicBlock(self.llfunc, cstr!(""));
+                        self.position_at_end(block);
+                    }
+                    _ => continue,
+                }
+            }
+            set_debug_line(self.bld, self.scope, line);
+            self.build_stmt(stmt);
+        }
+    }
+
+    /// Builds a loop body where break goes to `done` and continue to `next`.
+    /// The loop stacks and current block are restored even if the body
+    /// reports an error.
+    unsafe fn build_loop_body(
+        &mut self,
+        label: Option<crate::String>,
+        body: &Block,
+        done: LLVMBasicBlockRef,
+        next: LLVMBasicBlockRef,
+    ) {
+        let block = self.block;
+        self.break_dest.push((label, done));
+        self.continue_dest.push((label, next));
+        let result = catch_errors(|| self.build_block(body));
+        self.break_dest.pop();
+        self.continue_dest.pop();
+        if let Err(e) = result {
+            self.position_at_end(block);
+            raise(e);
+        }
+    }
+
+    unsafe fn param(&self, i: ParamId) -> LLVMValueRef {
+        match self.params[i] {
+            Some(v) => v,
+            None => panic!("unit param {} has no value", i),
+        }
+    }
+
+    /// Loads an aggregate into the registers it's passed in.
+    unsafe fn load_regs(
+        &self,
+        ty: TypeId,
+        p: LLVMValueRef,
+        regs: &[LLVMTypeRef],
+    ) -> Vec<LLVMValueRef> {
+        let (regs_ty, align) = self.regs_type(ty, regs);
+        let p = LLVMBuildBitCast(self.bld, p, LLVMPointerType(regs_ty, 0), cstr!(""));
+        let mut vs = vec![];
+        for (i, &reg) in regs.iter().enumerate() {
+            let q = LLVMBuildStructGEP2(self.bld, regs_ty, p, i as u32, cstr!(""));
+            let v = LLVMBuildLoad2(self.bld, reg, q, cstr!(""));
+            LLVMSetAlignment(v, align);
+            vs.push(v);
+        }
+        vs
+    }
+
+    unsafe fn store_regs(
+        &self,
+        ty: TypeId,
+        p: LLVMValueRef,
+        regs: &[LLVMTypeRef],
+        vs: &[LLVMValueRef],
+    ) {
+        let (regs_ty, align) = self.regs_type(ty, regs);
+        let p = LLVMBuildBitCast(self.bld, p, LLVMPointerType(regs_ty, 0), cstr!(""));
+        for (i, &v) in vs.iter().enumerate() {
+            let q = LLVMBuildStructGEP2(self.bld, regs_ty, p, i as u32, cstr!(""));
+            let store = LLVMBuildStore(self.bld, v, q);
+            LLVMSetAlignment(store, align);
+        }
+    }
+
+    /// A struct with the registers at their eightbyte offsets, and the
+    /// alignment the aggregate guarantees for accessing it.
+    unsafe fn regs_type(&self, ty: TypeId, regs: &[LLVMTypeRef]) -> (LLVMTypeRef, u32) {
+        let mut regs = regs.to_vec();
+        let regs_ty = LLVMStructType(regs.as_mut_ptr(), regs.len() as u32, 0);
+        let align = LLVMABIAlignmentOfType(self.tybld.layout, self.tybld.lltype(ty));
+        (regs_ty, align)
+    }
+
+    /// Whether code can still fall through the current block: it has no
+    /// terminator yet, and it's either the entry or something branches to it.
+    unsafe fn is_open(&self) -> bool {
+        LLVMGetBasicBlockTerminator(self.block).is_null()
+            && (self.block == LLVMGetEntryBasicBlock(self.llfunc)
+                || !LLVMGetFirstUse(LLVMBasicBlockAsValue(self.block)).is_null())
+    }
+
+    /// Falls through to `dest`, unless the current block is already
+    /// terminated or is dead, in which case it's closed with unreachable.
+    unsafe fn branch_to(&mut self, dest: LLVMBasicBlockRef) {
+        if self.is_open() {
+            LLVMBuildBr(self.bld, dest);
+        } else if LLVMGetBasicBlockTerminator(self.block).is_null() {
+            LLVMBuildUnreachable(self.bld);
         }
     }
 
@@ -366,21 +1774,43 @@ impl<'a> StmtBuilder<'a> {
 
     unsafe fn build_stmt(&mut self, stmt: &Stmt) {
         match stmt {
-            Stmt::Break => {
-                let block = match self.break_dest.last() {
-                    Some(&b) => b,
-                    None => panic!("can't break outside of loop"),
+            &Stmt::Break(label) => {
+                let block = match loop_dest(&self.break_dest, label) {
+                    Some(b) => b,
+                    None => match label {
+                        Some(label) => error(format!("can't break outside of loop {:?}", label)),
+                        None => error("can't break outside of loop"),
+                    },
                 };
                 LLVMBuildBr(self.bld, block);
             }
-            Stmt::Continue => {
-                let block = match self.continue_dest.last() {
-                    Some(&b) => b,
-                    None => panic!("can't continue outside of loop"),
+            &Stmt::Continue(label) => {
+                let block = match loop_dest(&self.continue_dest, label) {
+                    Some(b) => b,
+                    None => match label {
+                        Some(label) => error(format!("can't continue outside of loop {:?}", label)),
+                        None => error("can't continue outside of loop"),
+                    },
                 };
                 LLVMBuildBr(self.bld, block);
             }
-            Stmt::For(init, cond, post, body) => {
+            &Stmt::Label(i) => {
+                let block = self.labels[i];
+                LLVMMoveBasicBlockAfter(block, self.block);
+                self.branch_to(block);
+                self.position_at_end(block);
+            }
+            Stmt::IndirectBr(addr, dests) => {
+                let addr = self.build_scalar(addr);
+                let br = LLVMBuildIndirectBr(self.bld, addr, dests.len() as u32);
+                for &i in dests {
+                    LLVMAddDestination(br, self.labels[i]);
+                }
+                // Anything after the goto is unreachable until the next label.
+                let block = LLVMAppendBasicBlock(self.llfunc, cstr!(""));
+                self.position_at_end(block);
+            }
+            &Stmt::For(label, ref init, ref cond, ref post, ref body) => {
                 for stmt in init {
                     self.build_stmt(stmt);
                 }
@@ -395,24 +1825,18 @@ impl<'a> StmtBuilder<'a> {
                 LLVMBuildCondBr(self.bld, cond, then, done);
 
                 self.position_at_end(then);
-                self.break_dest.push(done);
-                self.continue_dest.push(tail);
-                self.build_block(body);
-                self.break_dest.pop();
-                self.continue_dest.pop();
-                if LLVMGetBasicBlockTerminator(self.block).is_null() {
-                    LLVMBuildBr(self.bld, tail);
-                }
+                self.build_loop_body(label, body, done, tail);
+                self.branch_to(tail);
 
                 self.position_at_end(tail);
                 for stmt in post {
                     self.build_stmt(stmt);
                 }
-                LLVMBuildBr(self.bld, head);
+                self.branch_to(head);
 
                 self.position_at_end(done);
             }
-            Stmt::While(cond, body) => {
+            &Stmt::While(label, ref cond, ref body) => {
                 let head = LLVMAppendBasicBlock(self.llfunc, cstr!(""));
                 let then = LLVMAppendBasicBlock(self.llfunc, cstr!(""));
                 let done = LLVMAppendBasicBlock(self.llfunc, cstr!(""));
@@ -423,98 +1847,171 @@ impl<'a> StmtBuilder<'a> {
                 LLVMBuildCondBr(self.bld, cond, then, done);
 
                 self.position_at_end(then);
-                self.break_dest.push(done);
-                self.continue_dest.push(head);
-                self.build_block(body);
-                self.break_dest.pop();
-                self.continue_dest.pop();
-                if LLVMGetBasicBlockTerminator(self.block).is_null() {
-                    LLVMBuildBr(self.bld, head);
-                }
+                self.build_loop_body(label, body, done, head);
+                self.branch_to(head);
 
                 self.position_at_end(done);
             }
-            Stmt::If(cond, body) => {
+            Stmt::If(cond, body, else_body) => {
                 let cond = self.build_scalar(cond);
                 let then = LLVMAppendBasicBlock(self.llfunc, cstr!(""));
+                let otherwise = match else_body {
+                    Some(_) => LLVMAppendBasicBlock(self.llfunc, cstr!("")),
+                    None => ptr::null_mut(),
+                };
                 let done = LLVMAppendBasicBlock(self.llfunc, cstr!(""));
-                LLVMBuildCondBr(self.bld, cond, then, done);
+                match else_body {
+                    Some(_) => LLVMBuildCondBr(self.bld, cond, then, otherwise),
+                    None => LLVMBuildCondBr(self.bld, cond, then, done),
+                };
                 LLVMPositionBuilderAtEnd(self.bld, then);
                 self.block = then;
                 self.build_block(body);
-                if LLVMGetBasicBlockTerminator(self.block).is_null() {
-                    LLVMBuildBr(self.bld, done);
+                self.branch_to(done);
+                if let Some(else_body) = else_body {
+                    self.position_at_end(otherwise);
+                    self.build_block(else_body);
+                    self.branch_to(done);
                 }
                 LLVMPositionBuilderAtEnd(self.bld, done);
                 self.block = done;
             }
+            Stmt::Match(x, arms, default) => {
+                let tag = self.build_enum_tag(x);
+                let otherwise = LLVMAppendBasicBlock(self.llfunc, cstr!(""));
+                let done = LLVMAppendBasicBlock(self.llfunc, cstr!(""));
+                let switch = LLVMBuildSwitch(self.bld, tag, otherwise, arms.len() as u32);
+                for &(variant, ref body) in arms {
+                    let then = LLVMAppendBasicBlock(self.llfunc, cstr!(""));
+                    LLVMMoveBasicBlockBefore(then, otherwise);
+                    let variant = self.tybld.variant_tag(x.ty, variant);
+                    let variant = LLVMConstInt(LLVMTypeOf(tag), variant as u64, 0);
+                    LLVMAddCase(switch, variant, then);
+                    self.position_at_end(then);
+                    self.build_block(body);
+                    self.branch_to(done);
+                }
+                self.position_at_end(otherwise);
+                if let Some(body) = default {
+                    self.build_block(body);
+                }
+                self.branch_to(done);
+                self.position_at_end(done);
+            }
             Stmt::Assign(x, y) => {
                 let p = self.build_place(x);
-                let _ = self.build_expr(y, Some(p));
+                let packed = self.in_packed(x);
+                match self.field_bits(x) {
+                    Some(bits) => {
+                        let v = self.build_scalar(y);
+                        self.store_bits(x.ty, p, v, bits, packed);
+                    }
+                    None if packed => self.build_unaligned(y, p),
+                    None => {
+                        let _ = self.build_expr(y, Some(p));
+                    }
+                }
             }
             Stmt::Return(x) => {
+                if let ExprKind::Call(_, _) = x.kind {
+                    self.tail_call = true;
+                }
                 let v = self.build_expr(x, self.sret);
-                match v {
-                    Value::Unit => LLVMBuildRetVoid(self.bld),
-                    Value::Aggregate(_) => LLVMBuildRetVoid(self.bld),
-                    Value::Scalar(v) => LLVMBuildRet(self.bld, v),
-                };
-            }
-            Stmt::Expr(x) => {
-                let _ = self.build_expr(x, None);
+                match (v, &self.ret) {
+                    (Value::Aggregate(p), Some(regs)) => {
+                        let mut vs = self.load_regs(x.ty, p, regs);
+                        match vs.len() {
+                            0 => LLVMBuildRetVoid(self.bld),
+                            1 => LLVMBuildRet(self.bld, vs[0]),
+                            n => LLVMBuildAggregateRet(self.bld, vs.as_mut_ptr(), n as u32),
+                        };
+                    }
+                    (Value::Unit, _) | (Value::Aggregate(_), None) => {
+                        LLVMBuildRetVoid(self.bld);
+                    }
+                    (Value::Scalar(v), _) => {
+                        LLVMBuildRet(self.bld, v);
+                    }
+                }
             }
+            Stmt::Expr(x) => self.build_discarded(x),
         }
     }
 
     unsafe fn build_place(&mut self, e: &Expr) -> LLVMValueRef {
         match &e.kind {
             &ExprKind::Local(i) => self.locals[i],
-            &ExprKind::Param(i) => LLVMGetParam(self.llfunc, i as u32),
-            ExprKind::Index(p, i) => {
-                let ptr = self.tybld.lltype(p.ty);
-                let elem = LLVMGetElementType(ptr);
-                let ty = self.tybld.irtype(p.ty);
-                let p = match self.tybld.irtype(p.ty).kind() {
-                    TypeKind::Aggregate => self.build_place(p),
-                    TypeKind::Scalar => {
-                        assert_eq!(ty.scalar_kind(), ScalarKind::Pointer);
-                        self.build_scalar(p)
-                    }
-                    TypeKind::Unit => panic!(),
+            &ExprKind::Global(i) => self.llglobals[i],
+            &ExprKind::Param(i) => self.param(i),
+            ExprKind::Index(_, _) => {
+                // Chained indexing into nested arrays, like a[i][j], is
+                // addressed with one GEP, the way C does it.
+                let mut indices = vec![];
+                let mut x = e;
+                while let ExprKind::Index(p, i) = &x.kind {
+                    indices.push(i);
+                    x = p;
+                    if let Type::Pointer(_) = self.tybld.irtype(x.ty) {
+                        break;
+                    }
+                }
+                let mut idxs = vec![];
+                let (p, ty) = match self.tybld.irtype(x.ty) {
+                    &Type::Pointer(elem) => (self.build_deref(x), elem),
+                    Type::Array(_, _) => {
+                        let intptr = LLVMIntPtrType(self.tybld.layout);
+                        idxs.push(LLVMConstInt(intptr, 0, 0));
+                        (self.build_place(x), x.ty)
+                    }
+                    ty => panic!("can't index {:?}", ty),
                 };
-                let i = self.build_scalar(i);
-                let mut idx = [i];
-                let pidx = idx.as_mut_ptr();
-                let nidx = idx.len() as u32;
-                LLVMBuildGEP2(self.bld, elem, p, pidx, nidx, cstr!(""))
+                let mut indexed = x.ty;
+                for i in indices.into_iter().rev() {
+                    let i_ty = i.ty;
+                    let i = self.build_scalar(i);
+                    let i = self.build_index(i, i_ty);
+                    indexed = match self.tybld.irtype(indexed) {
+                        &Type::Array(elem, n) => {
+                            if self.bounds_checks {
+                                self.check_bounds(i, n);
+                            }
+                            elem
+                        }
+                        &Type::Pointer(elem) => elem,
+                        ty => panic!("can't index {:?}", ty),
+                    };
+                    idxs.push(i);
+                }
+                let ty = self.tybld.lltype(ty);
+                let pidx = idxs.as_mut_ptr();
+                let nidx = idxs.len() as u32;
+                match self.tybld.irtype(x.ty) {
+                    Type::Array(_, _) => {
+                        LLVMBuildInBoundsGEP2(self.bld, ty, p, pidx, nidx, cstr!(""))
+                    }
+                    _ => LLVMBuildGEP2(self.bld, ty, p, pidx, nidx, cstr!("")),
+                }
             }
             &ExprKind::Field(ref x, i) => {
                 let ty = self.tybld.irtype(x.ty);
                 let (sty_id, p) = match ty {
-                    Type::Tuple(_) | Type::Struct(_) => (x.ty, self.build_place(x)),
-                    &Type::Pointer(ty) => (ty, self.build_scalar(x)),
+                    Type::Tuple(_) | Type::Struct(_) | Type::Union(_) => {
+                        (x.ty, self.build_place(x))
+                    }
+                    &Type::Pointer(ty) => (ty, self.build_deref(x)),
                     _ => panic!(),
                 };
+                if let Type::Union(_) = self.tybld.irtype(sty_id) {
+                    return self.union_field(sty_id, p, i);
+                }
+                let field = self.tybld.field(sty_id, i);
                 let sty = self.tybld.lltype(sty_id);
-                LLVMBuildStructGEP2(self.bld, sty, p, i, cstr!(""))
+                LLVMBuildStructGEP2(self.bld, sty, p, field.index, cstr!(""))
             }
-            ExprKind::Unary(Unop::Deref, p) => self.build_scalar(p),
+            ExprKind::Unary(Unop::Deref, p) => self.build_deref(p),
             &ExprKind::Func(i) => self.llfuncs[i],
             &ExprKind::EnumField(ref x, variant, i) => {
-                let enty = match self.tybld.irtype(x.ty) {
-                    Type::Enum(enty) => enty,
-                    _ => panic!(),
-                };
-                let variant_ty = {
-                    let mut xargs = vec![];
-                    for &arg in &enty.variants[variant as usize].args {
-                        let ty = self.tybld.lltype(arg);
-                        xargs.push(ty);
-                    }
-                    let p = xargs.as_mut_ptr();
-                    let n = xargs.len() as u32;
-                    LLVMStructType(p, n, 0)
-                };
+                let variant_ty = self.tybld.variant_type(x.ty, variant);
                 let ety = self.tybld.lltype(x.ty);
                 let enum_ptr = self.build_place(x);
                 let body_ptr = LLVMBuildStructGEP2(self.bld, ety, enum_ptr, 0, cstr!(""));
@@ -550,47 +2047,127 @@ impl<'a> StmtBuilder<'a> {
             TypeKind::Scalar => {
                 let v = self.build_scalar(e);
                 if let Some(dst) = dst {
-                    LLVMBuildStore(self.bld, v, dst);
+                    store_scalar(self.bld, v, dst);
                 }
                 Value::Scalar(v)
             }
         }
     }
 
+    /// Builds an expression statement, whose value is unused. Aggregates
+    /// only need a slot if a call's sret param has to point somewhere.
+    unsafe fn build_discarded(&mut self, e: &Expr) {
+        if let TypeKind::Aggregate = self.tybld.irtype(e.ty).kind() {
+            match &e.kind {
+                ExprKind::Call(func, args) if !self.returns_sret(func) => {
+                    let _ = self.build_call(func, args, None);
+                    return;
+                }
+                ExprKind::Local(_) | ExprKind::Param(_) | ExprKind::Global(_) => return,
+                _ => {}
+            }
+        }
+        let _ = self.build_expr(e, None);
+    }
+
+    unsafe fn returns_sret(&self, func: &Expr) -> bool {
+        let fnty = match self.tybld.irtype(func.ty) {
+            &Type::Func(_) => func.ty,
+            &Type::Pointer(fnty) => fnty,
+            _ => panic!(),
+        };
+        match self.tybld.irtype(fnty) {
+            Type::Func(fnty) => self.tybld.func_sig(fnty).1.sret.is_some(),
+            _ => panic!(),
+        }
+    }
+
     unsafe fn build_call(
         &mut self,
         func: &Expr,
         args: &[Expr],
         sret: Option<LLVMValueRef>,
     ) -> LLVMValueRef {
+        let tail = std::mem::replace(&mut self.tail_call, false);
         let fnty = match self.tybld.irtype(func.ty) {
             &Type::Func(_) => func.ty,
             &Type::Pointer(fnty) => fnty,
             _ => panic!(),
         };
+        let (layout, ret, cc, nparams) = match self.tybld.irtype(fnty) {
+            Type::Func(fnty) => {
+                let layout = self.tybld.func_sig(fnty).1;
+                (layout, fnty.ret, fnty.call_conv, fnty.params.len())
+            }
+            _ => panic!(),
+        };
         let fnty = self.tybld.lltype(fnty);
         let func = self.build_scalar(func);
         let mut args2 = vec![];
-        for arg in args {
-            let arg = self.build_expr(arg, None);
-            let arg = match arg {
-                Value::Unit => continue,
-                Value::Aggregate(p) => p,
-                Value::Scalar(v) => v,
+        for (i, arg) in args.iter().enumerate() {
+            let v = match self.build_expr(arg, None) {
+                Value::Scalar(v) if i >= nparams => Value::Scalar(self.promote_var_arg(arg.ty, v)),
+                v => v,
             };
-            args2.push(arg);
+            match (v, layout.params.get(i)) {
+                (Value::Unit, _) => {}
+                (Value::Aggregate(p), Some(ParamAbi::Split(_, regs))) => {
+                    args2.extend(self.load_regs(arg.ty, p, regs));
+                }
+                (Value::Aggregate(p), _) => args2.push(p),
+                (Value::Scalar(v), _) => args2.push(v),
+            }
         }
-        if let Some(sret) = sret {
-            args2.push(sret);
+        if let (Some(i), Some(sret)) = (layout.sret, sret) {
+            args2.insert(i as usize, sret);
         }
-        LLVMBuildCall2(
+        let call = LLVMBuildCall2(
             self.bld,
             fnty,
             func,
             args2.as_mut_ptr(),
             args2.len() as u32,
             cstr!(""),
-        )
+        );
+        for &(i, attr) in &layout.attrs {
+            LLVMAddCallSiteAttribute(call, i, attr);
+        }
+        LLVMSetInstructionCallConv(call, call_conv(cc));
+        if tail {
+            self.tail_calls.push(call);
+        }
+        if let (Some(regs), Some(dst)) = (&layout.ret, sret) {
+            let vs: Vec<_> = match regs.len() {
+                1 => vec![call],
+                n => (0..n as u32)
+                    .map(|i| LLVMBuildExtractValue(self.bld, call, i, cstr!("")))
+                    .collect(),
+            };
+            self.store_regs(ret, dst, regs, &vs);
+        }
+        call
+    }
+
+    /// C's default argument promotions for args passed through `...`:
+    /// floats are passed as doubles, and integers narrower than int as int.
+    unsafe fn promote_var_arg(&mut self, ty: TypeId, v: LLVMValueRef) -> LLVMValueRef {
+        let lltype = LLVMTypeOf(v);
+        match self.tybld.irtype(ty).scalar_kind() {
+            ScalarKind::Float if LLVMGetTypeKind(lltype) == LLVMTypeKind_LLVMFloatTypeKind => {
+                LLVMBuildFPExt(self.bld, v, LLVMDoubleType(), cstr!(""))
+            }
+            kind @ ScalarKind::Int | kind @ ScalarKind::UInt
+                if LLVMGetIntTypeWidth(lltype) < 32 =>
+            {
+                // Bools are i1, and true is 1, not -1.
+                if kind == ScalarKind::Int && LLVMGetIntTypeWidth(lltype) > 1 {
+                    LLVMBuildSExt(self.bld, v, LLVMInt32Type(), cstr!(""))
+                } else {
+                    LLVMBuildZExt(self.bld, v, LLVMInt32Type(), cstr!(""))
+                }
+            }
+            _ => v,
+        }
     }
 
     unsafe fn build_unit(&mut self, e: &Expr) {
@@ -599,10 +2176,87 @@ impl<'a> StmtBuilder<'a> {
             ExprKind::Call(func, args) => {
                 let _ = self.build_call(func, args, None);
             }
+            ExprKind::Cond(cond, x, y) => {
+                let _ = self.build_cond(cond, x, y, None);
+            }
+            ExprKind::InlineAsm {
+                template,
+                constraints,
+                operands,
+                clobbers,
+            } => self.build_inline_asm(template, constraints, operands, clobbers),
+            &ExprKind::Intrinsic(intrinsic, ref args) => {
+                let _ = self.build_intrinsic(intrinsic, args, e.ty);
+            }
             _ => panic!("expected (), got {:?}", e),
         }
     }
 
+    unsafe fn build_inline_asm(
+        &mut self,
+        template: &str,
+        constraints: &[crate::String],
+        operands: &[Expr],
+        clobbers: &[crate::String],
+    ) {
+        let mut outputs = vec![];
+        let mut output_tys = vec![];
+        let mut inputs = vec![];
+        let mut input_tys = vec![];
+        for (constraint, x) in constraints.iter().zip(operands) {
+            if constraint[1..].starts_with('=') {
+                outputs.push(self.build_place(x));
+                output_tys.push(self.tybld.lltype(x.ty));
+            } else {
+                inputs.push(self.build_scalar(x));
+                input_tys.push(self.tybld.lltype(x.ty));
+            }
+        }
+        let mut constraint = vec![];
+        for c in constraints {
+            constraint.push(unescape(c));
+        }
+        for c in clobbers {
+            constraint.push([&b"~{"[..], &unescape(c), b"}"].concat());
+        }
+        let mut constraint = constraint.join(&b',');
+        let mut template = unescape(template);
+
+        // Multiple outputs are returned as a struct.
+        let ret = match output_tys.len() {
+            0 => LLVMVoidType(),
+            1 => output_tys[0],
+            n => LLVMStructType(output_tys.as_mut_ptr(), n as u32, 0),
+        };
+        let nparams = input_tys.len() as u32;
+        let fnty = LLVMFunctionType(ret, input_tys.as_mut_ptr(), nparams, 0);
+        let asm = LLVMGetInlineAsm(
+            fnty,
+            template.as_mut_ptr() as *mut i8,
+            template.len(),
+            constraint.as_mut_ptr() as *mut i8,
+            constraint.len(),
+            1,
+            0,
+            LLVMInlineAsmDialect_LLVMInlineAsmDialectATT,
+            0,
+        );
+        let n = inputs.len() as u32;
+        let call = LLVMBuildCall2(self.bld, fnty, asm, inputs.as_mut_ptr(), n, cstr!(""));
+        match outputs.len() {
+            0 => {}
+            1 => {
+                let _ = store_scalar(self.bld, call, outputs[0]);
+            }
+            _ => {
+                for (i, &dst) in outputs.iter().enumerate() {
+                    let v = LLVMBuildExtractValue(self.bld, call, i as u32, cstr!(""));
+                    let _ = store_scalar(self.bld, v, dst);
+                }
+            }
+        }
+    }
+
     unsafe fn build_aggregate(&mut self, e: &Expr, dst: LLVMValueRef) {
         match &e.kind {
             ExprKind::Tuple(elems) => {
@@ -615,18 +2269,48 @@ impl<'a> StmtBuilder<'a> {
             }
             ExprKind::Struct(fields) => {
                 let sty = self.tybld.lltype(e.ty);
-                for (i, e) in fields {
-                    let i = *i as u32;
-                    let dst = LLVMBuildStructGEP2(self.bld, sty, dst, i, cstr!(""));
-                    let _ = self.build_expr(e, Some(dst));
+                let (nfields, packed) = match self.tybld.irtype(e.ty) {
+                    Type::Struct(irsty) => (irsty.fields.len(), irsty.packed),
+                    // A union literal sets one field.
+                    Type::Union(uty) => (uty.fields.len(), false),
+                    _ => panic!(),
+                };
+                // Zero the fields the literal leaves out, like C.
+                if fields.len() < nfields {
+                    let size = LLVMStoreSizeOfType(self.tybld.layout, sty);
+                    let size = LLVMConstInt(LLVMInt64Type(), size, 0);
+                    let align = LLVMABIAlignmentOfType(self.tybld.layout, sty);
+                    let zero = LLVMConstInt(LLVMInt8Type(), 0, 0);
+                    LLVMBuildMemSet(self.bld, dst, zero, size, align);
+                }
+                if let Type::Union(_) = self.tybld.irtype(e.ty) {
+                    let (i, x) = &fields[0];
+                    let dst = self.union_field(e.ty, dst, *i);
+                    let _ = self.build_expr(x, Some(dst));
+                    return;
+                }
+                for (i, x) in fields {
+                    let field = self.tybld.field(e.ty, *i);
+                    let dst = LLVMBuildStructGEP2(self.bld, sty, dst, field.index, cstr!(""));
+                    match field.bits {
+                        Some(bits) => {
+                            let v = self.build_scalar(x);
+                            self.store_bits(x.ty, dst, v, bits, packed);
+                        }
+                        None if packed => self.build_unaligned(x, dst),
+                        None => {
+                            let _ = self.build_expr(x, Some(dst));
+                        }
+                    }
                 }
             }
             ExprKind::Array(elems) => {
                 let aty = self.tybld.lltype(e.ty);
+                // Pointer width indices, the same as build_index.
+                let intptr = LLVMIntPtrType(self.tybld.layout);
                 for (i, e) in elems.iter().enumerate() {
-                    let i = i as u64;
-                    let z = LLVMConstInt(LLVMInt32Type(), 0, 0);
-                    let i = LLVMConstInt(LLVMInt32Type(), i, 0); // FIXME should sign extend?
+                    let z = LLVMConstInt(intptr, 0, 0);
+                    let i = LLVMConstInt(intptr, i as u64, 0);
                     let mut idxs = [z, i];
                     let idxs_ptr = idxs.as_mut_ptr();
                     let idxs_len = idxs.len() as u32;
@@ -635,45 +2319,74 @@ impl<'a> StmtBuilder<'a> {
                     let _ = self.build_expr(e, Some(dst));
                 }
             }
+            &ExprKind::ArrayRepeat(ref x, n) => {
+                let aty = self.tybld.lltype(e.ty);
+                if is_zero_const(x) {
+                    let size = LLVMABISizeOfType(self.tybld.layout, aty);
+                    let size = LLVMConstInt(LLVMInt64Type(), size, 0);
+                    let align = LLVMABIAlignmentOfType(self.tybld.layout, aty);
+                    let zero = LLVMConstInt(LLVMInt8Type(), 0, 0);
+                    LLVMBuildMemSet(self.bld, dst, zero, size, align);
+                    return;
+                }
+                let v = self.build_expr(x, None);
+                if n == 0 {
+                    return;
+                }
+                // for (i = 0; i < n; i++) dst[i] = v
+                let intptr = LLVMIntPtrType(self.tybld.layout);
+                let zero = LLVMConstInt(intptr, 0, 0);
+                let one = LLVMConstInt(intptr, 1, 0);
+                // The value may have ended in a block of its own.
+                let pre = self.block;
+                let body = LLVMAppendBasicBlock(self.llfunc, cstr!(""));
+                let done = LLVMAppendBasicBlock(self.llfunc, cstr!(""));
+                LLVMBuildBr(self.bld, body);
+                self.position_at_end(body);
+                let i = LLVMBuildPhi(self.bld, intptr, cstr!(""));
+                let mut idxs = [zero, i];
+                let idxs_ptr = idxs.as_mut_ptr();
+                let p = LLVMBuildInBoundsGEP2(self.bld, aty, dst, idxs_ptr, 2, cstr!(""));
+                match v {
+                    Value::Scalar(v) => {
+                        let _ = store_scalar(self.bld, v, p);
+                    }
+                    Value::Aggregate(src) => self.copy(x.ty, src, p),
+                    Value::Unit => {}
+                }
+                let next = LLVMBuildAdd(self.bld, i, one, cstr!(""));
+                let n = LLVMConstInt(intptr, n as u64, 0);
+                let more = LLVMBuildICmp(self.bld, LLVMIntPredicate_LLVMIntULT, next, n, cstr!(""));
+                LLVMBuildCondBr(self.bld, more, body, done);
+                let mut values = [zero, next];
+                let mut blocks = [pre, body];
+                LLVMAddIncoming(i, values.as_mut_ptr(), blocks.as_mut_ptr(), 2);
+                self.position_at_end(done);
+            }
             ExprKind::Call(func, args) => {
                 let _ = self.build_call(func, args, Some(dst));
             }
-            ExprKind::Param(i) => {
-                let i = *i as u32;
-                let param = LLVMGetParam(self.llfunc, i);
+            &ExprKind::Param(i) => {
+                let param = self.param(i);
                 self.copy(e.ty, param, dst);
             }
             ExprKind::Unary(Unop::Deref, p) => {
-                let p = self.build_scalar(p);
+                let p = self.build_deref(p);
                 self.copy(e.ty, p, dst);
             }
             &ExprKind::EnumCall(variant, ref args) => {
                 let ety = self.tybld.lltype(e.ty);
-                let tag_index: u32 = if args.len() == 0 { 0 } else { 1 };
-                // FIXME This is wrong! ^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^
-                //  This is based on the variant args length. It should be the same index for all
-                //  variants, but its not right now.
+                let tag_index = self.tybld.enum_tag_index(e.ty);
                 let tag_ptr = LLVMBuildStructGEP2(self.bld, ety, dst, tag_index, cstr!(""));
-                let tag_value = LLVMConstInt(LLVMInt8Type(), variant as u64, 0);
+                let tag_type = self.tybld.enum_tag_type(e.ty);
+                let tag_value = self.tybld.variant_tag(e.ty, variant);
+                let tag_value = LLVMConstInt(tag_type, tag_value as u64, 0);
                 LLVMBuildStore(self.bld, tag_value, tag_ptr);
                 // No body, skip setting args
                 if args.len() == 0 {
                     return;
                 }
-                let variant_ty = {
-                    let enty = match self.tybld.irtype(e.ty) {
-                        Type::Enum(enty) => enty,
-                        _ => panic!(),
-                    };
-                    let mut xargs = vec![];
-                    for &arg in &enty.variants[variant as usize].args {
-                        let ty = self.tybld.lltype(arg);
-                        xargs.push(ty);
-                    }
-                    let p = xargs.as_mut_ptr();
-                    let n = xargs.len() as u32;
-                    LLVMStructType(p, n, 0)
-                };
+                let variant_ty = self.tybld.variant_type(e.ty, variant);
                 let body_ptr = LLVMBuildStructGEP2(self.bld, ety, dst, 0, cstr!(""));
                 let variant_ptr = LLVMBuildPointerCast(
                     self.bld,
@@ -689,8 +2402,11 @@ impl<'a> StmtBuilder<'a> {
                 }
             }
             ExprKind::EnumField(_, _, _) => {
-                println!("enum field type {:?}", self.tybld.irtype(e.ty));
-                unimplemented!()
+                let p = self.build_place(e);
+                self.copy(e.ty, p, dst);
+            }
+            ExprKind::Cond(cond, x, y) => {
+                let _ = self.build_cond(cond, x, y, Some(dst));
             }
             ExprKind::Null
             | ExprKind::Unit
@@ -700,20 +2416,111 @@ impl<'a> StmtBuilder<'a> {
             | ExprKind::Type(_)
             | ExprKind::Unary(_, _)
             | ExprKind::Binary(_, _, _)
-            | ExprKind::String(_)
             | ExprKind::Cast(_, _)
             | ExprKind::Bool(_)
             | ExprKind::Char(_)
             | ExprKind::Sizeof(_)
+            | ExprKind::Alignof(_)
             | ExprKind::EnumVariant(_)
-            | ExprKind::EnumTag(_) => {
+            | ExprKind::EnumTag(_)
+            | ExprKind::Intrinsic(_, _)
+            | ExprKind::LabelAddr(_)
+            | ExprKind::InlineAsm { .. } => {
                 panic!("got scalar expression in aggregate place");
             }
-            ExprKind::Const(_) => unimplemented!(),
-            ExprKind::Field(_, _) | ExprKind::Index(_, _) | ExprKind::Local(_) => {
+            ExprKind::String(s) => {
+                // A char array initialized from a string, zero filled like C.
+                let aty = self.tybld.lltype(e.ty);
+                let n = LLVMGetArrayLength(aty);
+                let mut bytes = unescape(s);
+                bytes.resize(n as usize, 0);
+                let global = self.build_private_bytes(&bytes);
+                let size = LLVMConstInt(LLVMInt64Type(), n as u64, 0);
+                LLVMBuildMemCpy(self.bld, dst, 1, global, 1, size);
+            }
+            &ExprKind::Const(i) => {
+                LLVMBuildStore(self.bld, self.llconsts[i], dst);
+            }
+            ExprKind::Field(_, _)
+            | ExprKind::Index(_, _)
+            | ExprKind::Local(_)
+            | ExprKind::Global(_) => {
                 let p = self.build_place(e);
-                self.copy(e.ty, p, dst);
+                if self.in_packed(e) {
+                    self.copy_unaligned(e.ty, p, dst);
+                } else {
+                    self.copy(e.ty, p, dst);
+                }
+            }
+        }
+    }
+
+    /// Like the globals LLVMBuildGlobalStringPtr creates, but `bytes` may
+    /// contain NULs.
+    unsafe fn build_private_bytes(&mut self, bytes: &[u8]) -> LLVMValueRef {
+        let ptr = bytes.as_ptr() as *const i8;
+        let init = LLVMConstString(ptr, bytes.len() as u32, 1);
+        let llmodule = LLVMGetGlobalParent(self.llfunc);
+        let global = LLVMAddGlobal(llmodule, LLVMTypeOf(init), cstr!(""));
+        LLVMSetInitializer(global, init);
+        LLVMSetGlobalConstant(global, 1);
+        LLVMSetLinkage(global, LLVMLinkage_LLVMPrivateLinkage);
+        LLVMSetUnnamedAddress(global, LLVMUnnamedAddr_LLVMGlobalUnnamedAddr);
+        LLVMSetAlignment(global, 1);
+        global
+    }
+
+    /// Indices are extended to pointer width by the signedness of `ty`, so
+    /// negative ones walk backwards.
+    unsafe fn build_index(&mut self, i: LLVMValueRef, ty: TypeId) -> LLVMValueRef {
+        let intptr = LLVMIntPtrType(self.tybld.layout);
+        if LLVMGetIntTypeWidth(LLVMTypeOf(i)) < LLVMGetIntTypeWidth(intptr) {
+            if self.tybld.irtype(ty).scalar_kind() == ScalarKind::UInt {
+                return LLVMBuildZExt(self.bld, i, intptr, cstr!(""));
             }
+            return LLVMBuildSExt(self.bld, i, intptr, cstr!(""));
+        }
+        i
+    }
+
+    fn field_bits(&self, e: &Expr) -> Option<(u32, u32)> {
+        let (x, i) = match &e.kind {
+            &ExprKind::Field(ref x, i) => (x, i),
+            _ => return None,
+        };
+        let sty_id = match self.tybld.irtype(x.ty) {
+            &Type::Pointer(ty) => ty,
+            _ => x.ty,
+        };
+        self.tybld.field(sty_id, i).bits
+    }
+
+    /// Replaces `width` bits at `offset` in the integer at `p` with the low bits of `v`.
+    unsafe fn store_bits(
+        &mut self,
+        ty: TypeId,
+        p: LLVMValueRef,
+        v: LLVMValueRef,
+        bits: (u32, u32),
+        packed: bool,
+    ) {
+        let (offset, width) = bits;
+        let lltype = self.tybld.lltype(ty);
+        // Masks are built on the field's type, which can be wider than u64.
+        let size = LLVMGetIntTypeWidth(lltype);
+        let high = LLVMConstInt(lltype, (size - width) as u64, 0);
+        let shift = LLVMConstInt(lltype, offset as u64, 0);
+        let low_mask = LLVMConstLShr(LLVMConstAllOnes(lltype), high);
+        let hole_mask = LLVMConstNot(LLVMConstShl(low_mask, shift));
+        let v = LLVMBuildAnd(self.bld, v, low_mask, cstr!(""));
+        let v = LLVMBuildShl(self.bld, v, shift, cstr!(""));
+        let old = LLVMBuildLoad2(self.bld, lltype, p, cstr!(""));
+        let old_and = LLVMBuildAnd(self.bld, old, hole_mask, cstr!(""));
+        let v = LLVMBuildOr(self.bld, old_and, v, cstr!(""));
+        let store = LLVMBuildStore(self.bld, v, p);
+        if packed {
+            LLVMSetAlignment(old, 1);
+            LLVMSetAlignment(store, 1);
         }
     }
 
@@ -723,8 +2530,10 @@ impl<'a> StmtBuilder<'a> {
             TypeKind::Unit => {}
             TypeKind::Aggregate => {
                 let lltype = self.tybld.lltype(ty);
-                let v = LLVMBuildLoad2(self.bld, lltype, src, cstr!(""));
-                LLVMBuildStore(self.bld, v, dst);
+                let size = LLVMStoreSizeOfType(self.tybld.layout, lltype);
+                let size = LLVMConstInt(LLVMInt64Type(), size, 0);
+                let align = LLVMABIAlignmentOfType(self.tybld.layout, lltype);
+                LLVMBuildMemCpy(self.bld, dst, align, src, align, size);
             }
             TypeKind::Scalar => {
                 let lltype = self.tybld.lltype(ty);
@@ -734,24 +2543,90 @@ impl<'a> StmtBuilder<'a> {
         }
     }
 
+    /// Points `p`, a pointer to a union, at its field `i`.
+    unsafe fn union_field(&mut self, ty: TypeId, p: LLVMValueRef, i: u32) -> LLVMValueRef {
+        let field = self.tybld.members[ty][i as usize];
+        LLVMBuildPointerCast(self.bld, p, LLVMPointerType(field, 0), cstr!(""))
+    }
+
+    /// Builds `e` into a field of a packed struct.
+    unsafe fn build_unaligned(&mut self, e: &Expr, dst: LLVMValueRef) {
+        match self.build_expr(e, None) {
+            Value::Scalar(v) => {
+                let store = store_scalar(self.bld, v, dst);
+                LLVMSetAlignment(store, 1);
+            }
+            Value::Aggregate(src) => self.copy_unaligned(e.ty, src, dst),
+            Value::Unit => {}
+        }
+    }
+
+    /// Copies an aggregate to or from a field of a packed struct.
+    unsafe fn copy_unaligned(&mut self, ty: TypeId, src: LLVMValueRef, dst: LLVMValueRef) {
+        let lltype = self.tybld.lltype(ty);
+        let size = LLVMStoreSizeOfType(self.tybld.layout, lltype);
+        let size = LLVMConstInt(LLVMInt64Type(), size, 0);
+        LLVMBuildMemCpy(self.bld, dst, 1, src, 1, size);
+    }
+
+    /// Whether `e` is a place inside a packed struct, which may be at any
+    /// offset and so can only be accessed with alignment 1.
+    fn in_packed(&self, e: &Expr) -> bool {
+        match &e.kind {
+            ExprKind::Field(x, _) => {
+                let (sty, by_value) = match self.tybld.irtype(x.ty) {
+                    &Type::Pointer(ty) => (ty, false),
+                    _ => (x.ty, true),
+                };
+                let packed = match self.tybld.irtype(sty) {
+                    Type::Struct(sty) => sty.packed,
+                    _ => false,
+                };
+                packed || by_value && self.in_packed(x)
+            }
+            ExprKind::Index(x, _) | ExprKind::EnumField(x, _, _) => {
+                self.tybld.irtype(x.ty).kind() == TypeKind::Aggregate && self.in_packed(x)
+            }
+            _ => false,
+        }
+    }
+
     unsafe fn build_scalar(&mut self, e: &Expr) -> LLVMValueRef {
         match &e.kind {
             ExprKind::Index(_, _) | ExprKind::Field(_, _) | ExprKind::EnumField(_, _, _) => {
                 let p = self.build_place(e);
                 let elem_type = self.tybld.lltype(e.ty);
-                LLVMBuildLoad2(self.bld, elem_type, p, cstr!(""))
+                let v = LLVMBuildLoad2(self.bld, elem_type, p, cstr!(""));
+                if self.in_packed(e) {
+                    LLVMSetAlignment(v, 1);
+                }
+                match self.field_bits(e) {
+                    Some((offset, width)) => {
+                        // Shift the field to the top, then extend it back down.
+                        let size = LLVMGetIntTypeWidth(elem_type);
+                        let shl = LLVMConstInt(elem_type, (size - offset - width) as u64, 0);
+                        let shr = LLVMConstInt(elem_type, (size - width) as u64, 0);
+                        let v = LLVMBuildShl(self.bld, v, shl, cstr!(""));
+                        match self.tybld.irtype(e.ty).scalar_kind() {
+                            ScalarKind::UInt => LLVMBuildLShr(self.bld, v, shr, cstr!("")),
+                            _ => LLVMBuildAShr(self.bld, v, shr, cstr!("")),
+                        }
+                    }
+                    None => v,
+                }
             }
             ExprKind::Float(s) => {
                 let lltype = self.tybld.lltype(e.ty);
+                let s = s.replace('_', "");
                 let ptr = s.as_ptr() as *const i8;
                 let len = s.len() as u32;
                 LLVMConstRealOfStringAndSize(lltype, ptr, len)
             }
             ExprKind::Integer(s) => {
                 let lltype = self.tybld.lltype(e.ty);
+                let (s, radix) = int_radix(s);
                 let ptr = s.as_ptr() as *const i8;
                 let len = s.len() as u32;
-                let radix = 10;
                 LLVMConstIntOfStringAndSize(lltype, ptr, len, radix)
             }
             ExprKind::Local(i) => {
@@ -759,39 +2634,117 @@ impl<'a> StmtBuilder<'a> {
                 let p = self.locals[*i];
                 LLVMBuildLoad2(self.bld, lltype, p, cstr!(""))
             }
-            ExprKind::Param(i) => LLVMGetParam(self.llfunc, *i as u32),
+            ExprKind::Global(i) => {
+                let lltype = self.tybld.lltype(e.ty);
+                let p = self.llglobals[*i];
+                LLVMBuildLoad2(self.bld, lltype, p, cstr!(""))
+            }
+            &ExprKind::Param(i) => self.param(i),
             ExprKind::Func(i) => self.llfuncs[*i],
+            ExprKind::Binary(op @ Binop::LogicAnd, x, y)
+            | ExprKind::Binary(op @ Binop::LogicOr, x, y) => {
+                let x = self.build_scalar(x);
+                let lhs_block = self.block;
+                let rhs_block = LLVMAppendBasicBlock(self.llfunc, cstr!(""));
+                let done = LLVMAppendBasicBlock(self.llfunc, cstr!(""));
+                match op {
+                    Binop::LogicAnd => LLVMBuildCondBr(self.bld, x, rhs_block, done),
+                    _ => LLVMBuildCondBr(self.bld, x, done, rhs_block),
+                };
+                self.position_at_end(rhs_block);
+                let y = self.build_scalar(y);
+                // The rhs may have added blocks of its own.
+                let rhs_block = self.block;
+                LLVMBuildBr(self.bld, done);
+                self.position_at_end(done);
+                let phi = LLVMBuildPhi(self.bld, LLVMInt1Type(), cstr!(""));
+                let mut values = [x, y];
+                let mut blocks = [lhs_block, rhs_block];
+                LLVMAddIncoming(phi, values.as_mut_ptr(), blocks.as_mut_ptr(), 2);
+                phi
+            }
             ExprKind::Binary(op, x, y) => {
                 let irty = self.tybld.irtype(x.ty);
                 let kind = irty.scalar_kind();
-                let x = self.build_scalar(x);
-                let y = self.build_scalar(y);
+                let y_ty = y.ty;
+                let (x, y) = match (op, &x.kind, &y.kind) {
+                    // Build null with the other operand's exact pointer type.
+                    (Binop::Cmp(_), ExprKind::Null, _) => {
+                        let y = self.build_scalar(y);
+                        (LLVMConstPointerNull(LLVMTypeOf(y)), y)
+                    }
+                    (Binop::Cmp(_), _, ExprKind::Null) => {
+                        let x = self.build_scalar(x);
+                        (x, LLVMConstPointerNull(LLVMTypeOf(x)))
+                    }
+                    _ => (self.build_scalar(x), self.build_scalar(y)),
+                };
                 use Predicate::*;
                 use ScalarKind::*;
                 match (op, kind) {
+                    // Overflow is undefined, which lets LLVM reason about
+                    // loop induction variables.
+                    (Binop::Add, Int) if !self.wrapping => {
+                        LLVMBuildNSWAdd(self.bld, x, y, cstr!(""))
+                    }
+                    (Binop::Sub, Int) if !self.wrapping => {
+                        LLVMBuildNSWSub(self.bld, x, y, cstr!(""))
+                    }
+                    (Binop::Mul, Int) if !self.wrapping => {
+                        LLVMBuildNSWMul(self.bld, x, y, cstr!(""))
+                    }
+                    (Binop::Add, UInt) if !self.wrapping => {
+                        LLVMBuildNUWAdd(self.bld, x, y, cstr!(""))
+                    }
+                    (Binop::Sub, UInt) if !self.wrapping => {
+                        LLVMBuildNUWSub(self.bld, x, y, cstr!(""))
+                    }
+                    (Binop::Mul, UInt) if !self.wrapping => {
+                        LLVMBuildNUWMul(self.bld, x, y, cstr!(""))
+                    }
+
                     (Binop::Add, Int) => LLVMBuildAdd(self.bld, x, y, cstr!("")),
                     (Binop::Sub, Int) => LLVMBuildSub(self.bld, x, y, cstr!("")),
                     (Binop::Mul, Int) => LLVMBuildMul(self.bld, x, y, cstr!("")),
                     (Binop::Div, Int) => LLVMBuildSDiv(self.bld, x, y, cstr!("")),
+                    (Binop::Rem, Int) => LLVMBuildSRem(self.bld, x, y, cstr!("")),
                     (Binop::And, Int) => LLVMBuildAnd(self.bld, x, y, cstr!("")),
+                    (Binop::Or, Int) => LLVMBuildOr(self.bld, x, y, cstr!("")),
+                    (Binop::Xor, Int) => LLVMBuildXor(self.bld, x, y, cstr!("")),
                     (Binop::Shl, Int) => LLVMBuildShl(self.bld, x, y, cstr!("")),
-                    (Binop::Shr, Int) => LLVMBuildLShr(self.bld, x, y, cstr!("")),
+                    (Binop::Shr, Int) => LLVMBuildAShr(self.bld, x, y, cstr!("")),
+
+                    (Binop::Add, UInt) => LLVMBuildAdd(self.bld, x, y, cstr!("")),
+                    (Binop::Sub, UInt) => LLVMBuildSub(self.bld, x, y, cstr!("")),
+                    (Binop::Mul, UInt) => LLVMBuildMul(self.bld, x, y, cstr!("")),
+                    (Binop::Div, UInt) => LLVMBuildUDiv(self.bld, x, y, cstr!("")),
+                    (Binop::Rem, UInt) => LLVMBuildURem(self.bld, x, y, cstr!("")),
+                    (Binop::And, UInt) => LLVMBuildAnd(self.bld, x, y, cstr!("")),
+                    (Binop::Or, UInt) => LLVMBuildOr(self.bld, x, y, cstr!("")),
+                    (Binop::Xor, UInt) => LLVMBuildXor(self.bld, x, y, cstr!("")),
+                    (Binop::Shl, UInt) => LLVMBuildShl(self.bld, x, y, cstr!("")),
+                    (Binop::Shr, UInt) => LLVMBuildLShr(self.bld, x, y, cstr!("")),
 
                     (Binop::Add, Float) => LLVMBuildFAdd(self.bld, x, y, cstr!("")),
                     (Binop::Sub, Float) => LLVMBuildFSub(self.bld, x, y, cstr!("")),
                     (Binop::Mul, Float) => LLVMBuildFMul(self.bld, x, y, cstr!("")),
                     (Binop::Div, Float) => LLVMBuildFDiv(self.bld, x, y, cstr!("")),
+                    (Binop::Rem, Float) => LLVMBuildFRem(self.bld, x, y, cstr!("")),
 
                     (Binop::Add, Pointer) => {
                         let ptr = self.tybld.lltype(e.ty);
                         let elem = LLVMGetElementType(ptr);
+                        let y = self.build_index(y, y_ty);
                         let mut idx = [y];
                         let pidx = idx.as_mut_ptr();
                         let nidx = idx.len() as u32;
                         LLVMBuildGEP2(self.bld, elem, x, pidx, nidx, cstr!(""))
                     }
 
-                    (Binop::Sub, Pointer) => LLVMBuildPtrDiff(self.bld, x, y, cstr!("")),
+                    (Binop::Sub, Pointer) => {
+                        let elem = LLVMGetElementType(LLVMTypeOf(x));
+                        LLVMBuildPtrDiff2(self.bld, elem, x, y, cstr!(""))
+                    }
 
                     (Binop::Cmp(pred), _) => {
                         let pred = match (pred, kind) {
@@ -809,61 +2762,104 @@ impl<'a> StmtBuilder<'a> {
                             (Gt, Int) => LLVMIntPredicate_LLVMIntSGT,
                             (Lt, Int) => LLVMIntPredicate_LLVMIntSLT,
 
+                            (Eq, UInt) => LLVMIntPredicate_LLVMIntEQ,
+                            (Ne, UInt) => LLVMIntPredicate_LLVMIntNE,
+                            (Ge, UInt) => LLVMIntPredicate_LLVMIntUGE,
+                            (Le, UInt) => LLVMIntPredicate_LLVMIntULE,
+                            (Gt, UInt) => LLVMIntPredicate_LLVMIntUGT,
+                            (Lt, UInt) => LLVMIntPredicate_LLVMIntULT,
+
                             (Eq, Pointer) => LLVMIntPredicate_LLVMIntEQ,
                             (Ne, Pointer) => LLVMIntPredicate_LLVMIntNE,
-                            (Ge, Pointer) => LLVMIntPredicate_LLVMIntSGE,
-                            (Le, Pointer) => LLVMIntPredicate_LLVMIntSLE,
-                            (Gt, Pointer) => LLVMIntPredicate_LLVMIntSGT,
-                            (Lt, Pointer) => LLVMIntPredicate_LLVMIntSLT,
+                            (Ge, Pointer) => LLVMIntPredicate_LLVMIntUGE,
+                            (Le, Pointer) => LLVMIntPredicate_LLVMIntULE,
+                            (Gt, Pointer) => LLVMIntPredicate_LLVMIntUGT,
+                            (Lt, Pointer) => LLVMIntPredicate_LLVMIntULT,
                         };
                         let cmp = match kind {
                             Float => LLVMBuildFCmp,
                             Int => LLVMBuildICmp,
+                            UInt => LLVMBuildICmp,
                             Pointer => LLVMBuildICmp,
                         };
                         cmp(self.bld, pred, x, y, cstr!(""))
                     }
+                    (Binop::UnorderedCmp(pred), Float) => {
+                        let pred = match pred {
+                            Eq => LLVMRealPredicate_LLVMRealUEQ,
+                            Ne => LLVMRealPredicate_LLVMRealUNE,
+                            Ge => LLVMRealPredicate_LLVMRealUGE,
+                            Le => LLVMRealPredicate_LLVMRealULE,
+                            Gt => LLVMRealPredicate_LLVMRealUGT,
+                            Lt => LLVMRealPredicate_LLVMRealULT,
+                        };
+                        LLVMBuildFCmp(self.bld, pred, x, y, cstr!(""))
+                    }
                     (op, kind) => panic!("unimplemented {:?} {:?}", op, kind),
                 }
             }
             ExprKind::String(s) => {
-                let mut s = unescape(s);
-                s.push('\0');
-                let ptr = s.as_ptr() as *const i8;
-                LLVMBuildGlobalStringPtr(self.bld, ptr, cstr!(""))
+                let mut bytes = unescape(s);
+                bytes.push(0);
+                let global = self.build_private_bytes(&bytes);
+                let zero = LLVMConstInt(LLVMInt32Type(), 0, 0);
+                let mut idxs = [zero, zero];
+                let aty = LLVMArrayType(LLVMInt8Type(), bytes.len() as u32);
+                LLVMConstInBoundsGEP2(aty, global, idxs.as_mut_ptr(), idxs.len() as u32)
             }
             ExprKind::Call(func, args) => self.build_call(func, args, None),
             ExprKind::Cast(e, ty) => {
                 let dst_ty = self.tybld.irtype(*ty);
                 let src_ty = self.tybld.irtype(e.ty);
                 let dst_llty = self.tybld.lltype(*ty);
+                if let Type::Enum(_) = src_ty {
+                    let p = match self.build_expr(e, None) {
+                        Value::Aggregate(p) => p,
+                        v => panic!("expected aggregate, got {:?}", v),
+                    };
+                    let tag = self.load_enum_tag(e.ty, p);
+                    return LLVMBuildIntCast2(self.bld, tag, dst_llty, 0, cstr!(""));
+                }
                 let v = self.build_scalar(e);
                 match (src_ty, dst_ty) {
-                    (Type::I8, Type::I8)
-                    | (Type::I16, Type::I16)
-                    | (Type::I32, Type::I32)
-                    | (Type::I64, Type::I64)
-                    | (Type::F32, Type::F32)
-                    | (Type::F64, Type::F64) => v,
-
-                    (Type::I8, Type::I16)
-                    | (Type::I8, Type::I32)
-                    | (Type::I8, Type::I64)
-                    | (Type::I16, Type::I32)
-                    | (Type::I16, Type::I64)
-                    | (Type::I32, Type::I64) => LLVMBuildSExt(self.bld, v, dst_llty, cstr!("")),
-
-                    (Type::I64, Type::I32)
-                    | (Type::I64, Type::I16)
-                    | (Type::I64, Type::I8)
-                    | (Type::I32, Type::I16)
-                    | (Type::I32, Type::I8)
-                    | (Type::I16, Type::I8) => LLVMBuildTrunc(self.bld, v, dst_llty, cstr!("")),
-
-                    (Type::I32, Type::F32) | (Type::I32, Type::F64) => {
-                        LLVMBuildSIToFP(self.bld, v, dst_llty, cstr!(""))
-                    }
-                    (Type::F32, Type::I32) => LLVMBuildFPToSI(self.bld, v, dst_llty, cstr!("")),
+                    (Type::F32, Type::F32) | (Type::F64, Type::F64) | (Type::Bool, Type::Bool) => v,
+
+                    (Type::Bool, y) if y.int_bits().is_some() => {
+                        LLVMBuildZExt(self.bld, v, dst_llty, cstr!(""))
+                    }
+                    (x, Type::Bool) if x.int_bits().is_some() => {
+                        let zero = LLVMConstNull(LLVMTypeOf(v));
+                        LLVMBuildICmp(self.bld, LLVMIntPredicate_LLVMIntNE, v, zero, cstr!(""))
+                    }
+
+                    // Int to int: same width is a no-op, narrowing truncates, and widening
+                    // extends according to the signedness of the source.
+                    (x, y) if x.int_bits().is_some() && y.int_bits().is_some() => {
+                        let from = x.int_bits().unwrap();
+                        let to = y.int_bits().unwrap();
+                        if from == to {
+                            v
+                        } else if to < from {
+                            LLVMBuildTrunc(self.bld, v, dst_llty, cstr!(""))
+                        } else if x.scalar_kind() == ScalarKind::UInt {
+                            LLVMBuildZExt(self.bld, v, dst_llty, cstr!(""))
+                        } else {
+                            LLVMBuildSExt(self.bld, v, dst_llty, cstr!(""))
+                        }
+                    }
+
+                    (x, Type::F32) | (x, Type::F64) if x.int_bits().is_some() => {
+                        match x.scalar_kind() {
+                            ScalarKind::UInt => LLVMBuildUIToFP(self.bld, v, dst_llty, cstr!("")),
+                            _ => LLVMBuildSIToFP(self.bld, v, dst_llty, cstr!("")),
+                        }
+                    }
+                    (Type::F32, y) | (Type::F64, y) if y.int_bits().is_some() => {
+                        match y.scalar_kind() {
+                            ScalarKind::UInt => LLVMBuildFPToUI(self.bld, v, dst_llty, cstr!("")),
+                            _ => LLVMBuildFPToSI(self.bld, v, dst_llty, cstr!("")),
+                        }
+                    }
 
                     (Type::F32, Type::F64) => LLVMBuildFPExt(self.bld, v, dst_llty, cstr!("")),
                     (Type::F64, Type::F32) => LLVMBuildFPTrunc(self.bld, v, dst_llty, cstr!("")),
@@ -871,6 +2867,12 @@ impl<'a> StmtBuilder<'a> {
                     (Type::Pointer(_), Type::Pointer(_)) => {
                         LLVMBuildPointerCast(self.bld, v, dst_llty, cstr!(""))
                     }
+                    (Type::Pointer(_), y) if y.int_bits().is_some() => {
+                        LLVMBuildPtrToInt(self.bld, v, dst_llty, cstr!(""))
+                    }
+                    (x, Type::Pointer(_)) if x.int_bits().is_some() => {
+                        LLVMBuildIntToPtr(self.bld, v, dst_llty, cstr!(""))
+                    }
 
                     (x, y) => unimplemented!("{:?} {:?}", x, y),
                 }
@@ -878,14 +2880,42 @@ impl<'a> StmtBuilder<'a> {
             ExprKind::Bool(true) => LLVMConstInt(LLVMInt1Type(), 1, 0),
             ExprKind::Bool(false) => LLVMConstInt(LLVMInt1Type(), 0, 0),
             ExprKind::Unary(Unop::AddressOf, e) => self.build_place(e),
+            ExprKind::Unary(Unop::Neg, x) => {
+                let kind = self.tybld.irtype(x.ty).scalar_kind();
+                let x = self.build_scalar(x);
+                match kind {
+                    ScalarKind::Float => LLVMBuildFNeg(self.bld, x, cstr!("")),
+                    ScalarKind::Int | ScalarKind::UInt => LLVMBuildNeg(self.bld, x, cstr!("")),
+                    ScalarKind::Pointer => panic!("cannot negate a pointer"),
+                }
+            }
+            ExprKind::Unary(Unop::BitNot, x) => {
+                let kind = self.tybld.irtype(x.ty).scalar_kind();
+                assert!(kind == ScalarKind::Int || kind == ScalarKind::UInt);
+                let x = self.build_scalar(x);
+                LLVMBuildNot(self.bld, x, cstr!(""))
+            }
+            ExprKind::Unary(Unop::LogicNot, x) => {
+                let kind = self.tybld.irtype(x.ty).scalar_kind();
+                assert!(kind == ScalarKind::Int || kind == ScalarKind::UInt);
+                let x = self.build_scalar(x);
+                let zero = LLVMConstNull(LLVMTypeOf(x));
+                LLVMBuildICmp(self.bld, LLVMIntPredicate_LLVMIntEQ, x, zero, cstr!(""))
+            }
             ExprKind::Unary(Unop::Deref, p) => {
                 let lltype = self.tybld.lltype(e.ty);
-                let p = self.build_scalar(p);
+                let p = self.build_deref(p);
                 LLVMBuildLoad2(self.bld, lltype, p, cstr!(""))
             }
             ExprKind::Sizeof(ty) => {
                 let lltype = self.tybld.lltype(*ty);
-                LLVMSizeOf(lltype)
+                let size = LLVMABISizeOfType(self.tybld.layout, lltype);
+                LLVMConstInt(LLVMInt64Type(), size, 0)
+            }
+            ExprKind::Alignof(ty) => {
+                let lltype = self.tybld.lltype(*ty);
+                let align = LLVMABIAlignmentOfType(self.tybld.layout, lltype);
+                LLVMConstInt(LLVMInt64Type(), align as u64, 0)
             }
             ExprKind::Const(i) => self.llconsts[*i],
             ExprKind::Null => {
@@ -897,39 +2927,255 @@ impl<'a> StmtBuilder<'a> {
                 LLVMConstInt(lltype, c as u64, 0)
             }
             &ExprKind::EnumVariant(i) => {
-                assert_eq!(self.tybld.irtype(e.ty), &Type::I8);
                 let lltype = self.tybld.lltype(e.ty);
                 LLVMConstInt(lltype, i as u64, 0)
             }
-            ExprKind::EnumTag(en) => {
-                let p = self.build_place(en);
-                let enty = self.tybld.lltype(en.ty);
-                let tag_ptr = LLVMBuildStructGEP2(self.bld, enty, p, 1, cstr!(""));
-                LLVMBuildLoad2(self.bld, LLVMInt8Type(), tag_ptr, cstr!(""))
+            ExprKind::EnumTag(en) => self.build_enum_tag(en),
+            &ExprKind::LabelAddr(i) => LLVMBlockAddress(self.llfunc, self.labels[i]),
+            ExprKind::Cond(cond, x, y) => {
+                let arms = self.build_cond(cond, x, y, None);
+                let mut values = vec![];
+                let mut blocks = vec![];
+                for &(v, block) in &arms {
+                    match v {
+                        Value::Scalar(v) => values.push(v),
+                        _ => panic!("expected scalar, got {:?}", e),
+                    }
+                    blocks.push(block);
+                }
+                let phi = LLVMBuildPhi(self.bld, LLVMTypeOf(values[0]), cstr!(""));
+                LLVMAddIncoming(phi, values.as_mut_ptr(), blocks.as_mut_ptr(), 2);
+                phi
+            }
+            &ExprKind::Intrinsic(intrinsic, ref args) => {
+                self.build_intrinsic(intrinsic, args, e.ty)
             }
             _ => panic!("expected scalar, got {:?}", e),
         }
     }
-}
 
-fn unescape(s: &str) -> String {
-    let s = &s[1..s.len() - 1];
-    let mut x = String::with_capacity(s.len());
-    let mut backslash = false;
-    for c in s.chars() {
-        let escaped = backslash;
-        backslash = false;
-        let c = match c {
-            '\\' if !escaped => {
-                backslash = true;
-                continue;
+    /// Builds `x` if `cond` is true and `y` otherwise, returning the value
+    /// of each arm along with the block it ended in.
+    unsafe fn build_cond(
+        &mut self,
+        cond: &Expr,
+        x: &Expr,
+        y: &Expr,
+        dst: Option<LLVMValueRef>,
+    ) -> [(Value, LLVMBasicBlockRef); 2] {
+        let cond = self.build_scalar(cond);
+        let then = LLVMAppendBasicBlock(self.llfunc, cstr!(""));
+        let otherwise = LLVMAppendBasicBlock(self.llfunc, cstr!(""));
+        let done = LLVMAppendBasicBlock(self.llfunc, cstr!(""));
+        LLVMBuildCondBr(self.bld, cond, then, otherwise);
+
+        self.position_at_end(then);
+        let x = self.build_expr(x, dst);
+        let then = self.block;
+        LLVMBuildBr(self.bld, done);
+
+        self.position_at_end(otherwise);
+        let y = self.build_expr(y, dst);
+        let otherwise = self.block;
+        LLVMBuildBr(self.bld, done);
+
+        self.position_at_end(done);
+        [(x, then), (y, otherwise)]
+    }
+
+    unsafe fn build_enum_tag(&mut self, en: &Expr) -> LLVMValueRef {
+        let p = self.build_place(en);
+        self.load_enum_tag(en.ty, p)
+    }
+
+    unsafe fn load_enum_tag(&mut self, ty: TypeId, p: LLVMValueRef) -> LLVMValueRef {
+        let enty = self.tybld.lltype(ty);
+        let tag_index = self.tybld.enum_tag_index(ty);
+        let tag_ptr = LLVMBuildStructGEP2(self.bld, enty, p, tag_index, cstr!(""));
+        let tag_type = self.tybld.enum_tag_type(ty);
+        LLVMBuildLoad2(self.bld, tag_type, tag_ptr, cstr!(""))
+    }
+
+    unsafe fn build_intrinsic(
+        &mut self,
+        intrinsic: Intrinsic,
+        args: &[Expr],
+        ty: TypeId,
+    ) -> LLVMValueRef {
+        match intrinsic {
+            Intrinsic::Rdtsc => {
+                if !self.target_is_x86() {
+                    error("rdtsc is only supported on x86 targets");
+                }
+                self.call_intrinsic("llvm.readcyclecounter", &mut [], &mut [])
+            }
+            Intrinsic::SatAdd | Intrinsic::SatSub => {
+                let unsigned = self.tybld.irtype(args[0].ty).scalar_kind() == ScalarKind::UInt;
+                let name = match (intrinsic, unsigned) {
+                    (Intrinsic::SatAdd, false) => "llvm.sadd.sat",
+                    (Intrinsic::SatAdd, true) => "llvm.uadd.sat",
+                    (_, false) => "llvm.ssub.sat",
+                    (_, true) => "llvm.usub.sat",
+                };
+                let ty = self.tybld.lltype(args[0].ty);
+                let x = self.build_scalar(&args[0]);
+                let y = self.build_scalar(&args[1]);
+                self.call_intrinsic(name, &mut [ty], &mut [x, y])
             }
-            'n' if escaped => '\n',
-            't' if escaped => '\t',
-            '\\' if escaped => '\\',
-            _ => c,
+            Intrinsic::CheckedAdd | Intrinsic::CheckedSub | Intrinsic::CheckedMul => {
+                let unsigned = self.tybld.irtype(args[0].ty).scalar_kind() == ScalarKind::UInt;
+                let name = match (intrinsic, unsigned) {
+                    (Intrinsic::CheckedAdd, false) => "llvm.sadd.with.overflow",
+                    (Intrinsic::CheckedAdd, true) => "llvm.uadd.with.overflow",
+                    (Intrinsic::CheckedSub, false) => "llvm.ssub.with.overflow",
+                    (Intrinsic::CheckedSub, true) => "llvm.usub.with.overflow",
+                    (_, false) => "llvm.smul.with.overflow",
+                    (_, true) => "llvm.umul.with.overflow",
+                };
+                let ty = self.tybld.lltype(args[0].ty);
+                let x = self.build_scalar(&args[0]);
+                let y = self.build_scalar(&args[1]);
+                let pair = self.call_intrinsic(name, &mut [ty], &mut [x, y]);
+                let v = LLVMBuildExtractValue(self.bld, pair, 0, cstr!(""));
+                let overflow = LLVMBuildExtractValue(self.bld, pair, 1, cstr!(""));
+                self.trap_if(overflow);
+                v
+            }
+            Intrinsic::Trunc => {
+                let x = self.build_scalar(&args[0]);
+                let ty = self.tybld.lltype(ty);
+                LLVMBuildTrunc(self.bld, x, ty, cstr!(""))
+            }
+            Intrinsic::Memcpy | Intrinsic::Memset => {
+                let ptr_i8 = LLVMPointerType(LLVMInt8Type(), 0);
+                let i64 = LLVMInt64Type();
+                let dst = self.build_scalar(&args[0]);
+                let dst = LLVMBuildPointerCast(self.bld, dst, ptr_i8, cstr!(""));
+                let x = self.build_scalar(&args[1]);
+                let signed = self.tybld.irtype(args[2].ty).scalar_kind() == ScalarKind::Int;
+                let n = self.build_scalar(&args[2]);
+                let n = LLVMBuildIntCast2(self.bld, n, i64, signed as LLVMBool, cstr!(""));
+                let volatile = LLVMConstInt(LLVMInt1Type(), 0, 0);
+                match intrinsic {
+                    Intrinsic::Memcpy => {
+                        let src = LLVMBuildPointerCast(self.bld, x, ptr_i8, cstr!(""));
+                        let mut tys = [ptr_i8, ptr_i8, i64];
+                        let mut args = [dst, src, n, volatile];
+                        self.call_intrinsic("llvm.memcpy", &mut tys, &mut args)
+                    }
+                    _ => {
+                        let mut args = [dst, x, n, volatile];
+                        self.call_intrinsic("llvm.memset", &mut [ptr_i8, i64], &mut args)
+                    }
+                }
+            }
+            Intrinsic::Sqrt | Intrinsic::Fabs => {
+                let name = match intrinsic {
+                    Intrinsic::Sqrt => "llvm.sqrt",
+                    _ => "llvm.fabs",
+                };
+                let ty = self.tybld.lltype(ty);
+                let x = self.build_scalar(&args[0]);
+                self.call_intrinsic(name, &mut [ty], &mut [x])
+            }
+            Intrinsic::Unordered => panic!("unordered is lowered to a comparison"),
+        }
+    }
+
+    unsafe fn target_is_x86(&self) -> bool {
+        let llmodule = LLVMGetGlobalParent(self.llfunc);
+        is_x86(&CStr::from_ptr(LLVMGetTarget(llmodule)).to_string_lossy())
+    }
+
+    /// Branches to a `llvm.trap` block if `cond` is true.
+    unsafe fn trap_if(&mut self, cond: LLVMValueRef) {
+        let trap = LLVMAppendBasicBlock(self.llfunc, cstr!(""));
+        let ok = LLVMAppendBasicBlock(self.llfunc, cstr!(""));
+        LLVMBuildCondBr(self.bld, cond, trap, ok);
+        self.position_at_end(trap);
+        self.call_intrinsic("llvm.trap", &mut [], &mut []);
+        LLVMBuildUnreachable(self.bld);
+        self.position_at_end(ok);
+    }
+
+    /// Builds the pointer being dereferenced, trapping on null if null
+    /// checks are on.
+    unsafe fn build_deref(&mut self, p: &Expr) -> LLVMValueRef {
+        let p = self.build_scalar(p);
+        if self.null_checks {
+            let is_null = LLVMBuildIsNull(self.bld, p, cstr!(""));
+            self.trap_if(is_null);
+        }
+        p
+    }
+
+    /// Traps unless `0 <= i < n`. Negative indices compare as large
+    /// unsigned ones.
+    unsafe fn check_bounds(&mut self, i: LLVMValueRef, n: u32) {
+        let n = LLVMConstInt(LLVMTypeOf(i), n as u64, 0);
+        let out = LLVMBuildICmp(self.bld, LLVMIntPredicate_LLVMIntUGE, i, n, cstr!(""));
+        self.trap_if(out);
+    }
+
+    /// Calls the LLVM intrinsic `name`, overloaded on `tys`.
+    unsafe fn call_intrinsic(
+        &mut self,
+        name: &str,
+        tys: &mut [LLVMTypeRef],
+        args: &mut [LLVMValueRef],
+    ) -> LLVMValueRef {
+        let id = LLVMLookupIntrinsicID(name.as_ptr() as *const i8, name.len());
+        let llmodule = LLVMGetGlobalParent(self.llfunc);
+        let func = LLVMGetIntrinsicDeclaration(llmodule, id, tys.as_mut_ptr(), tys.len());
+        let fnty = LLVMIntrinsicGetType(LLVMGetGlobalContext(), id, tys.as_mut_ptr(), tys.len());
+        let p = args.as_mut_ptr();
+        let n = args.len() as u32;
+        LLVMBuildCall2(self.bld, fnty, func, p, n, cstr!(""))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds `f(i32 a, i32 b)`, which stores `a < b` (or just `a`, if not
+    /// `compare`) into a local of type `slot`, and returns the module's IR.
+    unsafe fn store_comparison(slot: LLVMTypeRef, compare: bool) -> std::string::String {
+        let module = Module(LLVMModuleCreateWithName(cstr!("store_scalar")));
+        let builder = Builder(LLVMCreateBuilder());
+        let mut params = [LLVMInt32Type(), LLVMInt32Type()];
+        let fnty = LLVMFunctionType(LLVMVoidType(), params.as_mut_ptr(), 2, 0);
+        let f = LLVMAddFunction(module.0, cstr!("f"), fnty);
+        LLVMPositionBuilderAtEnd(builder.0, LLVMAppendBasicBlock(f, cstr!("")));
+        let dst = LLVMBuildAlloca(builder.0, slot, cstr!("slot"));
+        let (a, b) = (LLVMGetParam(f, 0), LLVMGetParam(f, 1));
+        let v = match compare {
+            true => LLVMBuildICmp(builder.0, LLVMIntPredicate_LLVMIntSLT, a, b, cstr!("less")),
+            false => a,
         };
-        x.push(c);
+        store_scalar(builder.0, v, dst);
+        LLVMBuildRetVoid(builder.0);
+        assert_eq!(verify_report(module.0), Ok(()));
+        ir_string(module.0)
+    }
+
+    #[test]
+    fn store_comparison_into_wider_slot() {
+        let ir = unsafe { store_comparison(LLVMInt8Type(), true) };
+        assert!(ir.contains("zext i1 %less to i8"), "{}", ir);
+        assert!(ir.contains("i8* %slot"), "{}", ir);
+    }
+
+    #[test]
+    fn store_comparison_into_bool_slot() {
+        let ir = unsafe { store_comparison(LLVMInt1Type(), true) };
+        assert!(!ir.contains("zext"), "{}", ir);
+        assert!(ir.contains("store i1 %less, i1* %slot"), "{}", ir);
+    }
+
+    #[test]
+    #[should_panic(expected = "only i1 comparison results are widened")]
+    fn store_mismatched_int() {
+        unsafe { store_comparison(LLVMInt64Type(), false) };
     }
-    x
 }
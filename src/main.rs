@@ -1,13 +1,7 @@
-use llvm_sys;
+use mylang::{catch_errors, error, ir, llvm, CompileError};
 use std::env;
-use std::fmt;
 use std::fs;
-use std::ops::Deref;
-use std::process::exit;
-
-mod ir;
-mod llvm;
-mod syntax;
+use std::process::{exit, Command, ExitStatus};
 
 fn usage() {
     println!(
@@ -18,32 +12,245 @@ USAGE: mylangc [options] file...
 
 OPTIONS:
     -h | --help             Display available options.
+    -o <path>               Write the executable to <path> (default a.out).
+    -O0 | -O1 | -O2 | -O3   Set the optimization level.
+    -g                      Emit DWARF line tables for debuggers.
+    -fwrapv                 Make integer overflow wrap instead of being undefined.
+    -fcheck-null            Trap when dereferencing a null pointer.
+    -fcheck-bounds          Trap when indexing an array out of bounds.
+    -fvisibility=<vis>      Default symbol visibility: default, hidden, or protected.
     --print-llvm            Display generated LLVM IR.
+    --print-data-layout     Display the target data layout string.
+    --emit-llvm <path>      Write generated LLVM IR to <path>.
+    --emit-asm <path>       Write generated assembly to <path>.
+    --target <triple>       Compile for <triple> instead of the host.
+    --cpu <name>            Compile for CPU <name>, or native for the host.
+    --features <list>       Enable target features, e.g. +avx2,+fma.
+    --reloc <model>         Relocation model: default, static, or pic.
+    --function-sections     Place each function in its own section.
+    --symbol-prefix <pre>   Prefix the names of defined functions (except main).
+    --link-name <fn>=<sym>  Link calls to the external function <fn> to <sym>.
+    --jit                   Run main in memory instead of linking a.out.
 "
     );
 }
 
 struct Args {
     path: std::string::String,
+    out: std::string::String,
     print_ir: bool,
     print_llvm: bool,
+    print_data_layout: bool,
+    emit_llvm: Option<std::string::String>,
+    symbol_prefix: Option<std::string::String>,
+    emit_asm: Option<std::string::String>,
+    function_sections: bool,
+    opt_level: llvm::OptLevel,
+    target: Option<std::string::String>,
+    cpu: Option<std::string::String>,
+    features: Option<std::string::String>,
+    reloc_mode: llvm::RelocMode,
+    jit: bool,
+    debug_info: bool,
+    wrapping: bool,
+    null_checks: bool,
+    bounds_checks: bool,
+    visibility: ir::Visibility,
+    link_names: Vec<(std::string::String, std::string::String)>,
 }
 
 fn parse_args() -> Args {
     let mut args = Args {
         path: std::string::String::new(),
+        out: "a.out".into(),
         print_ir: false,
         print_llvm: false,
+        print_data_layout: false,
+        emit_llvm: None,
+        symbol_prefix: None,
+        emit_asm: None,
+        function_sections: false,
+        opt_level: llvm::OptLevel::None,
+        target: None,
+        cpu: None,
+        features: None,
+        reloc_mode: llvm::RelocMode::Default,
+        jit: false,
+        debug_info: false,
+        wrapping: false,
+        null_checks: false,
+        bounds_checks: false,
+        visibility: ir::Visibility::Default,
+        link_names: vec![],
     };
-    for arg in env::args().skip(1) {
+    let mut argv = env::args().skip(1);
+    while let Some(arg) = argv.next() {
         if arg == "-h" || arg == "--help" {
             usage();
-            error();
+            exit_error();
+        }
+        if arg == "-o" {
+            match argv.next() {
+                Some(path) => args.out = path,
+                None => {
+                    println!("missing path for -o");
+                    usage();
+                    exit_error();
+                }
+            }
+            continue;
         }
         if arg == "--print-llvm" {
             args.print_llvm = true;
             continue;
         }
+        if arg == "--print-data-layout" {
+            args.print_data_layout = true;
+            continue;
+        }
+        if arg == "--emit-llvm" {
+            match argv.next() {
+                Some(path) => args.emit_llvm = Some(path),
+                None => {
+                    println!("missing path for --emit-llvm");
+                    usage();
+                    exit_error();
+                }
+            }
+            continue;
+        }
+        let opt_level = match arg.as_str() {
+            "-O0" => Some(llvm::OptLevel::None),
+            "-O1" => Some(llvm::OptLevel::Less),
+            "-O2" => Some(llvm::OptLevel::Default),
+            "-O3" => Some(llvm::OptLevel::Aggressive),
+            _ => None,
+        };
+        if let Some(opt_level) = opt_level {
+            args.opt_level = opt_level;
+            continue;
+        }
+        if arg == "--target" {
+            match argv.next() {
+                Some(triple) => args.target = Some(triple),
+                None => {
+                    println!("missing triple for --target");
+                    usage();
+                    exit_error();
+                }
+            }
+            continue;
+        }
+        if arg == "--cpu" {
+            match argv.next() {
+                Some(cpu) => args.cpu = Some(cpu),
+                None => {
+                    println!("missing name for --cpu");
+                    usage();
+                    exit_error();
+                }
+            }
+            continue;
+        }
+        if arg == "--features" {
+            match argv.next() {
+                Some(features) => args.features = Some(features),
+                None => {
+                    println!("missing list for --features");
+                    usage();
+                    exit_error();
+                }
+            }
+            continue;
+        }
+        if arg == "--reloc" {
+            args.reloc_mode = match argv.next().as_ref().map(|s| s.as_str()) {
+                Some("default") => llvm::RelocMode::Default,
+                Some("static") => llvm::RelocMode::Static,
+                Some("pic") => llvm::RelocMode::Pic,
+                model => {
+                    println!("invalid relocation model {:?}", model);
+                    usage();
+                    exit_error();
+                }
+            };
+            continue;
+        }
+        if arg == "--function-sections" {
+            args.function_sections = true;
+            continue;
+        }
+        if arg == "--emit-asm" {
+            match argv.next() {
+                Some(path) => args.emit_asm = Some(path),
+                None => {
+                    println!("missing path for --emit-asm");
+                    usage();
+                    exit_error();
+                }
+            }
+            continue;
+        }
+        if arg == "--symbol-prefix" {
+            match argv.next() {
+                Some(prefix) => args.symbol_prefix = Some(prefix),
+                None => {
+                    println!("missing prefix for --symbol-prefix");
+                    usage();
+                    exit_error();
+                }
+            }
+            continue;
+        }
+        if arg == "--link-name" {
+            let rename = argv.next();
+            let rename = rename.as_ref().and_then(|s| {
+                let i = s.find('=')?;
+                Some((s[..i].to_string(), s[i + 1..].to_string()))
+            });
+            match rename {
+                Some(rename) => args.link_names.push(rename),
+                None => {
+                    println!("expected <fn>=<sym> for --link-name");
+                    usage();
+                    exit_error();
+                }
+            }
+            continue;
+        }
+        if arg == "-fwrapv" {
+            args.wrapping = true;
+            continue;
+        }
+        if arg == "-fcheck-null" {
+            args.null_checks = true;
+            continue;
+        }
+        if arg == "-fcheck-bounds" {
+            args.bounds_checks = true;
+            continue;
+        }
+        if arg.starts_with("-fvisibility=") {
+            args.visibility = match &arg["-fvisibility=".len()..] {
+                "default" => ir::Visibility::Default,
+                "hidden" => ir::Visibility::Hidden,
+                "protected" => ir::Visibility::Protected,
+                vis => {
+                    println!("invalid visibility {:?}", vis);
+                    usage();
+                    exit_error();
+                }
+            };
+            continue;
+        }
+        if arg == "-g" {
+            args.debug_info = true;
+            continue;
+        }
+        if arg == "--jit" {
+            args.jit = true;
+            continue;
+        }
         if arg == "--print-ir" {
             args.print_ir = true;
             continue;
@@ -51,61 +258,23 @@ fn parse_args() -> Args {
         if args.path != "" {
             println!("multiple file arguments: {:?}, {:?}", args.path, arg);
             usage();
-            error();
+            exit_error();
         }
         args.path = arg;
     }
     if args.path == "" {
         println!("missing file argument");
         usage();
-        error();
+        exit_error();
     }
     args
 }
 
-fn error() -> ! {
+fn exit_error() -> ! {
     println!("# compilation error, exiting with error code 1...");
     exit(1)
 }
 
-#[derive(Copy, Clone, PartialEq, Eq, Hash, Default)]
-pub struct String(u16);
-
-impl Deref for String {
-    type Target = str;
-    fn deref(&self) -> &str {
-        let i = self.0 as usize;
-        unsafe { &INTERN[i] }
-    }
-}
-
-impl fmt::Debug for String {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:?}", self.deref())
-    }
-}
-
-impl fmt::Display for String {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.deref())
-    }
-}
-
-static mut INTERN: Vec<std::string::String> = Vec::new();
-
-pub fn intern(s: &str) -> String {
-    let intern = unsafe { &mut INTERN };
-    for (i, interned) in intern.iter().enumerate() {
-        let i = i as u16;
-        if s == interned {
-            return String(i);
-        }
-    }
-    let i = intern.len() as u16;
-    intern.push(s.into());
-    String(i)
-}
-
 fn print_cursor(text: &str, start: usize, end: usize) {
     println!();
     let line_start = match text[..start].rfind('\n') {
@@ -133,36 +302,122 @@ fn main() {
     let text = &match fs::read_to_string(&args.path) {
         Err(e) => {
             println!("unable to read {:?}: {}", args.path, e);
-            error();
+            exit_error();
         }
         Ok(s) => s,
     };
-    let module = syntax::parse(text);
-    let module = ir::build(&module);
-    unsafe {
-        let (machine, module) = llvm::build(&module);
-        if args.print_llvm {
-            llvm_sys::LLVMDumpModule(module);
+    if let Err(e) = run(&args, text) {
+        if let Some((start, end)) = e.span {
+            print_cursor(text, start, end);
         }
-        llvm::verify(module);
-        llvm::emit_object(machine, module);
+        println!("{}", e.message);
+        exit_error();
+    }
+}
+
+/// Compiles `text` and does whatever `args` ask for with it.
+fn run(args: &Args, text: &str) -> Result<(), CompileError> {
+    let options = llvm_options(args);
+    let inspect = args.print_llvm
+        || args.print_data_layout
+        || args.emit_llvm.is_some()
+        || args.emit_asm.is_some()
+        || args.jit;
+    if inspect {
+        let module = mylang::lower(text)?;
+        catch_errors(|| unsafe { inspect_module(args, &module, &options) })?;
+    } else {
+        mylang::compile(text, &args.path, &options, "a.o")?;
+    }
+    catch_errors(|| link(args))
+}
+
+fn llvm_options(args: &Args) -> llvm::Options {
+    let mut options = llvm::Options::default();
+    options.function_sections = args.function_sections;
+    options.opt_level = args.opt_level;
+    options.triple = args.target.clone();
+    options.reloc_mode = args.reloc_mode;
+    options.debug_info = args.debug_info;
+    options.wrapping = args.wrapping;
+    options.null_checks = args.null_checks;
+    options.bounds_checks = args.bounds_checks;
+    options.visibility = args.visibility;
+    options.link_names = args.link_names.iter().cloned().collect();
+    if args.cpu.as_ref().map(|cpu| cpu.as_str()) == Some("native") {
+        unsafe { options.native_cpu() };
+    } else {
+        options.cpu = args.cpu.clone();
+    }
+    if args.features.is_some() {
+        options.features = args.features.clone();
+    }
+    if let Some(prefix) = args.symbol_prefix.clone() {
+        options.name_mangler = Some(Box::new(move |name| match name {
+            "main" => name.to_string(),
+            _ => format!("{}{}", prefix, name),
+        }));
+    }
+    options
+}
+
+/// Like `mylang::compile`, but prints or writes the module along the way
+/// as `args` ask, or runs it with the JIT.
+unsafe fn inspect_module(args: &Args, module: &ir::Module2, options: &llvm::Options) {
+    let (machine_guard, module_guard) = llvm::build(module, &args.path, options);
+    let machine = machine_guard.0;
+    let module = module_guard.0;
+    if args.print_llvm {
+        print!("{}", llvm::ir_string(module));
     }
-    ld("a.o", "a.out");
+    if args.print_data_layout {
+        let layout = llvm_sys::LLVMGetModuleDataLayout(module);
+        println!("{}", llvm::data_layout_string(layout));
+    }
+    if let Some(path) = &args.emit_llvm {
+        llvm::emit_ir(machine, module, path);
+    }
+    llvm::finish(module, options);
+    if let Some(path) = &args.emit_asm {
+        llvm::emit_assembly(machine, module, path);
+    }
+    if args.jit {
+        exit(llvm::jit_run(module_guard, "main"));
+    }
+    if let Err(msg) = llvm::emit_object(machine, module, "a.o") {
+        error(msg);
+    }
+}
+
+fn link(args: &Args) {
+    let pie = args.reloc_mode == llvm::RelocMode::Pic;
+    let status = link_executable(&["a.o"], &args.out, pie);
     let _ = fs::remove_file("a.o");
+    if !status.success() {
+        error(format!("linking {:?} failed: {}", args.out, status));
+    }
 }
 
-fn ld(path: &str, out: &str) {
-    use std::process::Command;
-    let mut gcc = Command::new("gcc");
-    gcc.arg(path);
-    gcc.arg("-no-pie");
-    gcc.arg("-o");
-    gcc.arg(out);
+/// Links `objects` into an executable with the C compiler driver, `$CC` or
+/// else `cc`, printing its output.
+fn link_executable(objects: &[&str], out: &str, pie: bool) -> ExitStatus {
+    let cc = env::var("CC").unwrap_or_else(|_| "cc".into());
+    // $CC can carry a wrapper or flags, like "ccache gcc" or "gcc -m64".
+    let mut words = cc.split_whitespace();
+    let mut cmd = Command::new(words.next().unwrap_or("cc"));
+    cmd.args(words);
+    cmd.args(objects);
+    if !pie {
+        cmd.arg("-no-pie");
+    }
+    cmd.arg("-o");
+    cmd.arg(out);
+    // Float remainder lowers to fmod.
+    cmd.arg("-lm");
 
-    let output = match gcc.output() {
+    let output = match cmd.output() {
         Err(e) => {
-            println!("error linking object file: {}", e);
-            error();
+            error(format!("error running linker {:?}: {}", cc, e));
         }
         Ok(x) => x,
     };
@@ -170,4 +425,5 @@ fn ld(path: &str, out: &str) {
     let stdout = std::string::String::from_utf8_lossy(&output.stdout);
     let stderr = std::string::String::from_utf8_lossy(&output.stderr);
     print!("{}{}", stdout, stderr);
+    output.status
 }
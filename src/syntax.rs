@@ -1,12 +1,12 @@
-use crate::error;
+use crate::error_at;
 use crate::intern;
-use crate::print_cursor;
 use crate::String;
 
 #[derive(Debug)]
 pub struct Module<'a> {
     pub text: &'a str,
     pub const_decls: Vec<ConstDecl>,
+    pub global_decls: Vec<GlobalDecl>,
     pub type_decls: Vec<TypeDecl>,
     pub func_decls: Vec<FuncDecl>,
     pub func_bodys: Vec<FuncBody>,
@@ -24,6 +24,7 @@ pub fn parse(text: &str) -> Module {
     let mut func_decls = vec![];
     let mut func_bodys = vec![];
     let mut const_decls = vec![];
+    let mut global_decls = vec![];
     while p.token != EOF {
         match p.token {
             CONST => {
@@ -34,29 +35,47 @@ pub fn parse(text: &str) -> Module {
                 let type_decl = p.parse_type_decl();
                 type_decls.push(type_decl);
             }
-            FN => {
-                let decl = p.parse_func_decl();
+            VAR | WEAK | FN | NAME => {
+                let start = p.start;
+                let (linkage, visibility) = p.parse_linkage();
+                if p.token == VAR {
+                    let global_decl = p.parse_global_decl(linkage, visibility);
+                    global_decls.push(global_decl);
+                    continue;
+                }
+                let decl = p.parse_func_decl(linkage, visibility);
                 let id = func_decls.len();
                 func_decls.push(decl);
                 if p.token == SEMICOLON {
+                    let linkage = func_decls[id].linkage;
+                    if linkage != Linkage::External {
+                        error_at(
+                            p.start,
+                            p.end,
+                            format!("{} function must have a body", linkage.name()),
+                        );
+                    }
                     p.next();
                     continue;
                 }
 
                 let body = p.parse_block();
-                let body = FuncBody { id: id, body: body };
+                let body = FuncBody {
+                    id: id,
+                    body: body,
+                    span: (start as u16, p.start as u16),
+                };
                 func_bodys.push(body);
             }
             _ => {
-                print_cursor(p.text, p.start, p.start + 1);
-                println!("expected type or function");
-                error();
+                error_at(p.start, p.start + 1, "expected type or function");
             }
         }
     }
     Module {
         text: text,
         const_decls: const_decls,
+        global_decls: global_decls,
         type_decls: type_decls,
         func_decls: func_decls,
         func_bodys: func_bodys,
@@ -73,12 +92,21 @@ pub enum Token {
     CONTINUE,
     NULL,
     CONST,
+    VAR,
     SIZEOF,
+    ALIGNOF,
+    ASM,
     STAREQ,
     SLASHEQ,
     PLUSEQ,
     MINUSEQ,
     AMPERSAND,
+    ANDAND,
+    PIPE,
+    OROR,
+    CARET,
+    TILDE,
+    BANG,
     FOR,
     WHILE,
     EQ,
@@ -88,6 +116,8 @@ pub enum Token {
     GT,
     GE,
     IF,
+    ELSE,
+    MATCH,
     LBRACKET,
     RBRACKET,
     LPARENS,
@@ -95,12 +125,17 @@ pub enum Token {
     LBRACE,
     RBRACE,
     COLON,
+    QUESTION,
     COMMA,
     SEMICOLON,
     STAR,
     ARROW,
+    FATARROW,
     ASSIGN,
     FN,
+    WEAK,
+    GOTO,
+    LABEL,
     AS,
     LET,
     RETURN,
@@ -116,6 +151,7 @@ pub enum Token {
     TYPE,
     STRUCT,
     SLASH,
+    PERCENT,
     TRUE,
     FALSE,
     EOF,
@@ -128,7 +164,8 @@ pub enum Type {
     Name(String),
     Pointer(Box<Type>),
     Func(Box<FuncType>),
-    Array(u32, Box<Type>),
+    // The length is a constant expression.
+    Array(Box<Expr>, Box<Type>),
     Tuple(Vec<Type>),
 }
 
@@ -140,7 +177,10 @@ pub struct TypeDecl {
 
 #[derive(Debug, Clone)]
 pub enum TypeDeclKind {
-    Struct(Vec<(String, Type)>),
+    // name, type, bit-field width; and whether it's packed
+    Struct(Vec<(String, Type, Option<u32>)>, bool),
+    // Fields all start at offset 0, like a C union.
+    Union(Vec<(String, Type)>),
     Enum(Vec<EnumVariant>),
     Alias(Type),
 }
@@ -149,6 +189,9 @@ pub enum TypeDeclKind {
 pub struct EnumVariant {
     pub name: String,
     pub args: Vec<Type>,
+    // Explicit tag value, like `NotFound = 404`.
+    pub discriminant: Option<u32>,
+    pub span: (u16, u16),
 }
 
 #[derive(Debug, Clone)]
@@ -162,6 +205,16 @@ pub struct FuncType {
     pub params: Vec<Type>,
     pub ret: Type,
     pub var_args: bool,
+    pub call_conv: CallConv,
+}
+
+/// Written before `fn` in a declaration, like `fastcc fn step()`. Function
+/// pointer types are always C.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum CallConv {
+    C,
+    Fast,
+    Cold,
 }
 
 #[derive(Debug)]
@@ -169,6 +222,60 @@ pub struct FuncDecl {
     pub name: String,
     pub params: Vec<String>,
     pub ty: FuncType,
+    pub linkage: Linkage,
+    // None uses the module's default visibility.
+    pub visibility: Option<Visibility>,
+    pub attrs: Vec<FuncAttr>,
+}
+
+/// Who can see a symbol, written before `fn` or `var`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Linkage {
+    // Visible to other objects, the default.
+    External,
+    // Only visible in this module, like C's static.
+    Internal,
+    // Definitions in multiple objects are merged by the linker.
+    Weak,
+    // Like weak, but unused definitions can be dropped and all of them
+    // must be the same, like C++ inline functions.
+    LinkOnceOdr,
+}
+
+impl Linkage {
+    pub fn name(self) -> &'static str {
+        match self {
+            Linkage::External => "external",
+            Linkage::Internal => "internal",
+            Linkage::Weak => "weak",
+            Linkage::LinkOnceOdr => "linkonce_odr",
+        }
+    }
+}
+
+/// Whether a symbol is exported from a shared object, like ELF visibility.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Visibility {
+    Default,
+    // Not exported from the shared object it's defined in.
+    Hidden,
+    // Exported, but can't be preempted by other shared objects.
+    Protected,
+}
+
+impl Default for Visibility {
+    fn default() -> Self {
+        Visibility::Default
+    }
+}
+
+/// Optimization hints written before `fn`, like `cold fn fail()`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FuncAttr {
+    NoInline,
+    AlwaysInline,
+    Cold,
+    NoReturn,
 }
 
 #[derive(Debug)]
@@ -178,20 +285,39 @@ pub struct ConstDecl {
     pub value: Expr,
 }
 
+#[derive(Debug)]
+pub struct GlobalDecl {
+    pub name: String,
+    pub ty: Option<Type>,
+    // None declares a global defined in another object.
+    pub value: Option<Expr>,
+    pub linkage: Linkage,
+    pub visibility: Option<Visibility>,
+}
+
 #[derive(Debug)]
 pub struct FuncBody {
     // FIXME use something else to associate with FuncDecl?
     pub id: usize,
     pub body: Block,
+    pub span: (u16, u16),
 }
 
 #[derive(Debug, Clone)]
 pub struct Block {
     pub stmts: Vec<Stmt>,
+    // Span of each statement, for debug line numbers.
+    pub spans: Vec<(u16, u16)>,
 }
 
 #[derive(Debug, Clone)]
-pub enum Pattern {
+pub struct Pattern {
+    pub kind: PatternKind,
+    pub span: (u16, u16),
+}
+
+#[derive(Debug, Clone)]
+pub enum PatternKind {
     Name(String),
     Tuple(Vec<Pattern>),
     EnumVariant(String, Vec<Pattern>),
@@ -202,14 +328,19 @@ pub enum Stmt {
     Let(Pattern, Option<Type>, Option<Expr>),
     Return(Expr),
     Expr(Expr),
-    IfLet(Pattern, Expr, Block),
-    If(Expr, Block),
-    While(Expr, Block),
+    IfLet(Pattern, Expr, Block, Option<Block>),
+    If(Expr, Block, Option<Block>),
+    Match(Expr, Vec<(Pattern, Block)>),
+    // Loops and break/continue take an optional loop label.
+    While(Option<String>, Expr, Block),
     Assign(Expr, Expr),
-    For(Box<Stmt>, Expr, Box<Stmt>, Block),
+    For(Option<String>, Box<Stmt>, Expr, Box<Stmt>, Block),
     OpAssign(Token, Expr, Expr),
-    Break,
-    Continue,
+    Break(Option<String>),
+    Continue(Option<String>),
+    Label(String),
+    // goto *addr
+    Goto(Expr),
 }
 
 #[derive(Debug, Clone)]
@@ -230,6 +361,8 @@ pub enum ExprKind {
     Call(Box<Expr>, Vec<Expr>),
     Struct(Vec<(String, Expr)>),
     Array(Vec<Expr>),
+    // [value; count]
+    ArrayRepeat(Box<Expr>, Box<Expr>),
     Tuple(Vec<Expr>),
     Field(Box<Expr>, String),
     TupleField(Box<Expr>, u32),
@@ -237,8 +370,21 @@ pub enum ExprKind {
     Cast(Box<Expr>, Type),
     Bool(bool),
     Sizeof(Type),
+    Alignof(Type),
     Char(u8),
     Null,
+    // &&label
+    LabelAddr(String),
+    // cond ? x : y
+    Cond(Box<Expr>, Box<Expr>, Box<Expr>),
+    // asm("template" : "=r"(out), ... : "r"(in), ... : "clobber", ...)
+    // Outputs come first in constraints/operands, their constraints start with '='.
+    InlineAsm {
+        template: String,
+        constraints: Vec<String>,
+        operands: Vec<Expr>,
+        clobbers: Vec<String>,
+    },
 }
 
 pub struct Parser<'a> {
@@ -248,7 +394,36 @@ pub struct Parser<'a> {
     pub token: Token,
 }
 
-fn parse_int(text: &[u8]) -> (Token, usize) {
+/// Splits an integer literal into its sign, its digits after any
+/// `0x`/`0o`/`0b` prefix, and its radix.
+pub fn split_radix(s: &str) -> (&str, &str, u32) {
+    let (sign, s) = match s.strip_prefix('-') {
+        Some(s) => ("-", s),
+        None => ("", s),
+    };
+    let radix = match s.get(..2) {
+        Some("0x") => 16,
+        Some("0o") => 8,
+        Some("0b") => 2,
+        _ => return (sign, s, 10),
+    };
+    (sign, &s[2..], radix)
+}
+
+fn parse_int(text: &str) -> (Token, usize) {
+    let (_, digits, radix) = split_radix(text);
+    if radix != 10 {
+        // Take every alphanumeric so a bad digit is reported, not split off.
+        let mut n = text.len() - digits.len();
+        for c in digits.bytes() {
+            if !c.is_ascii_alphanumeric() && c != b'_' {
+                break;
+            }
+            n += 1;
+        }
+        return (INTEGER, n);
+    }
+    let text = text.as_bytes();
     let mut t = INTEGER;
     let mut n = 1;
     for c in &text[n..] {
@@ -256,7 +431,7 @@ fn parse_int(text: &[u8]) -> (Token, usize) {
             (INTEGER, b'.') => {
                 t = FLOAT;
             }
-            (_, c) if c.is_ascii_digit() => {}
+            (_, c) if c.is_ascii_digit() || *c == b'_' => {}
             _ => break,
         }
         n += 1;
@@ -294,9 +469,14 @@ impl<'a> Parser<'a> {
             '+' if d == '=' => (PLUSEQ, 2),
             '*' if d == '=' => (STAREQ, 2),
             '/' if d == '=' => (SLASHEQ, 2),
-            '-' if d.is_ascii_digit() => parse_int(text),
+            '-' if d.is_ascii_digit() => parse_int(&self.text[self.start..]),
             '-' => (MINUS, 1),
+            '&' if d == '&' => (ANDAND, 2),
             '&' => (AMPERSAND, 1),
+            '|' if d == '|' => (OROR, 2),
+            '|' => (PIPE, 1),
+            '^' => (CARET, 1),
+            '~' => (TILDE, 1),
             '+' => (PLUS, 1),
             '(' => (LPARENS, 1),
             ')' => (RPARENS, 1),
@@ -305,8 +485,11 @@ impl<'a> Parser<'a> {
             '[' => (LBRACKET, 1),
             ']' => (RBRACKET, 1),
             '/' => (SLASH, 1),
+            '%' => (PERCENT, 1),
             '=' if d == '=' => (EQ, 2),
+            '=' if d == '>' => (FATARROW, 2),
             '!' if d == '=' => (NE, 2),
+            '!' => (BANG, 1),
             '<' if d == '=' => (LE, 2),
             '>' if d == '=' => (GE, 2),
             '<' => (LT, 1),
@@ -316,6 +499,7 @@ impl<'a> Parser<'a> {
             ';' => (SEMICOLON, 1),
             '*' => (STAR, 1),
             ':' => (COLON, 1),
+            '?' => (QUESTION, 1),
             '.' if d == '.' && e == '.' => (ELLIPSIS, 3),
             '.' => (DOT, 1),
             '"' | '\'' => {
@@ -326,13 +510,18 @@ impl<'a> Parser<'a> {
                     if !escaped && b == quote {
                         break;
                     }
+                    if escaped && b == b'x' {
+                        let digits = text.get(n + 1..n + 3).unwrap_or(&[]);
+                        if digits.len() != 2 || !digits.iter().all(u8::is_ascii_hexdigit) {
+                            let start = self.start + n - 1;
+                            error_at(start, start + 2, "expected two hex digits after \\x");
+                        }
+                    }
                     escaped = !escaped && b == b'\\';
                     n += 1;
                 }
                 if text.get(n) != Some(&quote) {
-                    print_cursor(self.text, self.start, self.start + 1);
-                    println!("unterminated literal");
-                    error();
+                    error_at(self.start, self.start + 1, "unterminated literal");
                 }
                 n += 1;
                 let t = match quote {
@@ -358,11 +547,19 @@ impl<'a> Parser<'a> {
                     b"continue" => CONTINUE,
                     b"null" => NULL,
                     b"const" => CONST,
+                    b"var" => VAR,
                     b"sizeof" => SIZEOF,
+                    b"alignof" => ALIGNOF,
+                    b"asm" => ASM,
                     b"for" => FOR,
                     b"while" => WHILE,
                     b"if" => IF,
+                    b"else" => ELSE,
+                    b"match" => MATCH,
                     b"fn" => FN,
+                    b"weak" => WEAK,
+                    b"goto" => GOTO,
+                    b"label" => LABEL,
                     b"as" => AS,
                     b"let" => LET,
                     b"return" => RETURN,
@@ -374,13 +571,34 @@ impl<'a> Parser<'a> {
                 };
                 (token, n)
             }
-            _ if c.is_ascii_digit() => parse_int(text),
+            _ if c.is_ascii_digit() => parse_int(&self.text[self.start..]),
             _ => {
-                print_cursor(self.text, self.start, self.start + 1);
-                println!("unexpected character {:?}", c);
-                error();
+                error_at(
+                    self.start,
+                    self.start + 1,
+                    format!("unexpected character {:?}", c),
+                );
             }
         };
+        if token == INTEGER || token == FLOAT {
+            let (_, digits, radix) = split_radix(&self.text[self.start..self.start + n]);
+            let is_digit = |c: char| c.is_digit(radix) || c == '_' || token == FLOAT && c == '.';
+            if digits.is_empty() || !digits.chars().all(is_digit) {
+                let message = match token {
+                    FLOAT => "invalid float literal".to_string(),
+                    _ => format!("invalid base {} integer literal", radix),
+                };
+                error_at(self.start, self.start + n, message);
+            }
+            let stray = digits.starts_with('_') || digits.ends_with('_');
+            if stray || digits.contains("_.") || digits.contains("._") {
+                error_at(
+                    self.start,
+                    self.start + n,
+                    "`_` in a numeric literal must be between digits",
+                );
+            }
+        }
         self.token = token;
         self.end += n;
     }
@@ -425,31 +643,180 @@ impl<'a> Parser<'a> {
         ConstDecl { name, ty, value }
     }
 
-    fn parse_enum_variant(&mut self) -> EnumVariant {
+    /// Parses the linkage and visibility written before `fn` or `var`, like
+    /// `internal` or `weak hidden`.
+    pub fn parse_linkage(&mut self) -> (Linkage, Option<Visibility>) {
+        let mut linkage = None;
+        let mut visibility = None;
+        while self.parse_linkage_word(&mut linkage, &mut visibility) {}
+        (linkage.unwrap_or(Linkage::External), visibility)
+    }
+
+    /// Parses one linkage or visibility keyword into `linkage` or
+    /// `visibility`, returning false if the token isn't one.
+    fn parse_linkage_word(
+        &mut self,
+        linkage: &mut Option<Linkage>,
+        visibility: &mut Option<Visibility>,
+    ) -> bool {
+        let word = match self.token {
+            WEAK => intern("weak"),
+            NAME => self.token_string(),
+            _ => return false,
+        };
+        let (l, v) = match &*word {
+            "internal" => (Some(Linkage::Internal), None),
+            "weak" => (Some(Linkage::Weak), None),
+            "linkonce_odr" => (Some(Linkage::LinkOnceOdr), None),
+            "default" => (None, Some(Visibility::Default)),
+            "hidden" => (None, Some(Visibility::Hidden)),
+            "protected" => (None, Some(Visibility::Protected)),
+            _ => return false,
+        };
+        if (l.is_some() && linkage.is_some()) || (v.is_some() && visibility.is_some()) {
+            let msg = match l {
+                Some(_) => "symbol already has a linkage",
+                None => "symbol already has a visibility",
+            };
+            error_at(self.start, self.end, msg);
+        }
+        if l == Some(Linkage::Internal) || *linkage == Some(Linkage::Internal) {
+            if v.or(*visibility)
+                .map_or(false, |v| v != Visibility::Default)
+            {
+                error_at(
+                    self.start,
+                    self.end,
+                    "internal symbols can't be hidden or protected",
+                );
+            }
+        }
+        *linkage = l.or(*linkage);
+        *visibility = v.or(*visibility);
+        self.next();
+        true
+    }
+
+    pub fn parse_global_decl(
+        &mut self,
+        linkage: Linkage,
+        visibility: Option<Visibility>,
+    ) -> GlobalDecl {
+        self.parse(VAR);
         let name = self.token_string();
+        let start = self.start;
+        let end = self.end;
         self.parse(NAME);
-        if self.token != LPARENS {
-            return EnumVariant { name, args: vec![] };
+        let ty = match self.token {
+            COLON => {
+                self.next();
+                let ty = self.parse_type();
+                Some(ty)
+            }
+            _ => None,
+        };
+        let value = match self.token {
+            ASSIGN => {
+                self.next();
+                Some(self.parse_expr())
+            }
+            _ => None,
+        };
+        if ty.is_none() && value.is_none() {
+            error_at(start, end, "global declaration needs a type");
         }
-        self.next();
+        if value.is_none() && linkage != Linkage::External {
+            error_at(
+                start,
+                end,
+                format!("{} global must have a value", linkage.name()),
+            );
+        }
+        self.parse(SEMICOLON);
+        GlobalDecl {
+            name,
+            ty,
+            value,
+            linkage,
+            visibility,
+        }
+    }
+
+    fn parse_enum_variant(&mut self) -> EnumVariant {
+        let start = self.start;
+        let name = self.token_string();
+        let mut end = self.end;
+        self.parse(NAME);
         let mut args = vec![];
-        while self.token != RPARENS {
-            let arg = self.parse_type();
-            args.push(arg);
-            if self.token != COMMA {
-                break;
+        if self.token == LPARENS {
+            self.next();
+            while self.token != RPARENS {
+                let arg = self.parse_type();
+                args.push(arg);
+                if self.token != COMMA {
+                    break;
+                }
+                self.next();
             }
+            end = self.end;
+            self.parse(RPARENS);
+        }
+        let mut discriminant = None;
+        if self.token == ASSIGN {
             self.next();
+            let s = self.token_string();
+            let i = self.start;
+            let j = self.end;
+            end = j;
+            self.parse(INTEGER);
+            let s = s.replace('_', "");
+            let (_, digits, radix) = split_radix(&s);
+            match u32::from_str_radix(digits, radix) {
+                Ok(n) => discriminant = Some(n),
+                Err(e) => {
+                    error_at(i, j, format!("unable to parse enum discriminant: {}", e));
+                }
+            }
+        }
+        EnumVariant {
+            name,
+            args,
+            discriminant,
+            span: (start as u16, end as u16),
         }
-        self.parse(RPARENS);
-        EnumVariant { name, args }
     }
 
     pub fn parse_type_decl(&mut self) -> TypeDecl {
         self.parse(TYPE);
         let name = self.token_string();
         self.parse(NAME);
+        // Packed structs have no padding, like wire formats.
+        let packed = self.token == NAME && &*self.token_string() == "packed";
+        if packed {
+            self.next();
+            if self.token != STRUCT {
+                error_at(self.start, self.end, "only structs can be packed");
+            }
+        }
         let kind = match self.token {
+            NAME if &*self.token_string() == "union" => {
+                self.next();
+                self.parse(LBRACE);
+                let mut fields = vec![];
+                while self.token != RBRACE {
+                    let name = self.token_string();
+                    self.parse(NAME);
+                    self.parse(COLON);
+                    let ty = self.parse_type();
+                    fields.push((name, ty));
+                    if self.token != COMMA {
+                        break;
+                    }
+                    self.next();
+                }
+                self.parse(RBRACE);
+                TypeDeclKind::Union(fields)
+            }
             ENUM => {
                 self.next();
                 self.parse(LBRACE);
@@ -474,14 +841,34 @@ impl<'a> Parser<'a> {
                     self.parse(NAME);
                     self.parse(COLON);
                     let ty = self.parse_type();
-                    fields.push((name, ty));
+                    let bits = match self.token {
+                        COLON => {
+                            self.next();
+                            let s = self.token_string();
+                            let i = self.start;
+                            let j = self.end;
+                            self.parse(INTEGER);
+                            match s.parse() {
+                                Ok(n) => Some(n),
+                                Err(e) => {
+                                    error_at(
+                                        i,
+                                        j,
+                                        format!("unable to parse bit-field width: {}", e),
+                                    );
+                                }
+                            }
+                        }
+                        _ => None,
+                    };
+                    fields.push((name, ty, bits));
                     if self.token != COMMA {
                         break;
                     }
                     self.next();
                 }
                 self.parse(RBRACE);
-                TypeDeclKind::Struct(fields)
+                TypeDeclKind::Struct(fields, packed)
             }
             ASSIGN => {
                 self.next();
@@ -490,15 +877,75 @@ impl<'a> Parser<'a> {
                 TypeDeclKind::Alias(ty)
             }
             _ => {
-                print_cursor(self.text, self.start, self.end);
-                println!("expected struct or alias type declaration");
-                error();
+                error_at(
+                    self.start,
+                    self.end,
+                    "expected struct, union, enum, or alias type declaration",
+                );
             }
         };
         TypeDecl { name, kind }
     }
 
-    pub fn parse_func_decl(&mut self) -> FuncDecl {
+    pub fn parse_func_decl(
+        &mut self,
+        linkage: Linkage,
+        visibility: Option<Visibility>,
+    ) -> FuncDecl {
+        // Linkage and visibility can also be mixed in with the attributes.
+        let mut linkage = Some(linkage).filter(|&l| l != Linkage::External);
+        let mut visibility = visibility;
+        let mut attrs = vec![];
+        let mut call_conv = CallConv::C;
+        while self.token == NAME || self.token == WEAK {
+            if self.parse_linkage_word(&mut linkage, &mut visibility) {
+                continue;
+            }
+            let cc = match &*self.token_string() {
+                "fastcc" => Some(CallConv::Fast),
+                "coldcc" => Some(CallConv::Cold),
+                _ => None,
+            };
+            if let Some(cc) = cc {
+                if call_conv != CallConv::C {
+                    error_at(
+                        self.start,
+                        self.end,
+                        "function already has a calling convention",
+                    );
+                }
+                call_conv = cc;
+                self.next();
+                continue;
+            }
+            let attr = match &*self.token_string() {
+                "noinline" => FuncAttr::NoInline,
+                "alwaysinline" => FuncAttr::AlwaysInline,
+                "cold" => FuncAttr::Cold,
+                "noreturn" => FuncAttr::NoReturn,
+                name => {
+                    error_at(
+                        self.start,
+                        self.end,
+                        format!("unknown function attribute {:?}", name),
+                    );
+                }
+            };
+            let conflict = match attr {
+                FuncAttr::NoInline => Some(FuncAttr::AlwaysInline),
+                FuncAttr::AlwaysInline => Some(FuncAttr::NoInline),
+                _ => None,
+            };
+            if conflict.map_or(false, |other| attrs.contains(&other)) {
+                error_at(
+                    self.start,
+                    self.end,
+                    "noinline and alwaysinline can't be combined",
+                );
+            }
+            attrs.push(attr);
+            self.next();
+        }
         self.parse(FN);
 
         let name = self.token_string();
@@ -510,6 +957,13 @@ impl<'a> Parser<'a> {
         self.parse(LPARENS);
         while self.token != RPARENS {
             if self.token == ELLIPSIS {
+                if call_conv != CallConv::C {
+                    error_at(
+                        self.start,
+                        self.end,
+                        "variadic functions must use the C calling convention",
+                    );
+                }
                 self.next();
                 var_args = true;
                 break;
@@ -541,38 +995,50 @@ impl<'a> Parser<'a> {
             params: param_types,
             ret: ret.into(),
             var_args: var_args,
+            call_conv: call_conv,
         };
 
         FuncDecl {
             name: name,
             params: params,
             ty: ty,
+            linkage: linkage.unwrap_or(Linkage::External),
+            visibility: visibility,
+            attrs: attrs,
         }
     }
 
     pub fn parse_block(&mut self) -> Block {
         self.parse(LBRACE);
         let mut stmts = vec![];
+        let mut spans = vec![];
         while self.token != RBRACE {
+            let start = self.start;
             let stmt = self.parse_stmt();
             stmts.push(stmt);
+            spans.push((start as u16, self.start as u16));
             if self.token == SEMICOLON {
                 self.next();
             }
         }
         self.parse(RBRACE);
 
-        Block { stmts }
+        Block { stmts, spans }
     }
 
     fn parse_pattern(&mut self) -> Pattern {
-        match self.token {
+        let start = self.start;
+        let kind = match self.token {
             NAME => {
                 let name = self.token_string();
+                let end = self.end;
                 self.next();
 
                 if self.token != LPARENS {
-                    return Pattern::Name(name);
+                    return Pattern {
+                        kind: PatternKind::Name(name),
+                        span: (start as u16, end as u16),
+                    };
                 }
                 self.next();
                 let mut elems = vec![];
@@ -584,9 +1050,7 @@ impl<'a> Parser<'a> {
                     }
                     self.next();
                 }
-                self.parse(RPARENS);
-
-                Pattern::EnumVariant(name, elems)
+                PatternKind::EnumVariant(name, elems)
             }
             LPARENS => {
                 self.next();
@@ -599,26 +1063,50 @@ impl<'a> Parser<'a> {
                     }
                     self.next();
                 }
-                self.parse(RPARENS);
-                Pattern::Tuple(elems)
+                PatternKind::Tuple(elems)
             }
             _ => {
-                print_cursor(self.text, self.start, self.end);
-                println!("expected pattern");
-                error();
+                error_at(self.start, self.end, "expected pattern");
             }
+        };
+        let end = self.end;
+        self.parse(RPARENS);
+        Pattern {
+            kind: kind,
+            span: (start as u16, end as u16),
+        }
+    }
+
+    fn parse_loop_label(&mut self) -> Option<String> {
+        if self.token != NAME {
+            return None;
         }
+        let name = self.token_string();
+        self.next();
+        Some(name)
     }
 
     fn parse_stmt(&mut self) -> Stmt {
         match self.token {
             BREAK => {
                 self.next();
-                Stmt::Break
+                Stmt::Break(self.parse_loop_label())
             }
             CONTINUE => {
                 self.next();
-                Stmt::Continue
+                Stmt::Continue(self.parse_loop_label())
+            }
+            LABEL => {
+                self.next();
+                let name = self.token_string();
+                self.parse(NAME);
+                Stmt::Label(name)
+            }
+            GOTO => {
+                self.next();
+                self.parse(STAR);
+                let e = self.parse_expr();
+                Stmt::Goto(e)
             }
             FOR => {
                 self.next();
@@ -628,13 +1116,13 @@ impl<'a> Parser<'a> {
                 self.parse(SEMICOLON);
                 let post = self.parse_stmt();
                 let body = self.parse_block();
-                Stmt::For(init.into(), cond, post.into(), body)
+                Stmt::For(None, init.into(), cond, post.into(), body)
             }
             WHILE => {
                 self.next();
                 let cond = self.parse_expr();
                 let body = self.parse_block();
-                Stmt::While(cond, body)
+                Stmt::While(None, cond, body)
             }
             IF => {
                 self.next();
@@ -651,11 +1139,46 @@ impl<'a> Parser<'a> {
 
                 let cond = self.parse_expr();
                 let body = self.parse_block();
+                let else_body = match self.token {
+                    ELSE => {
+                        self.next();
+                        match self.token {
+                            // else if is sugar for an else block holding the if.
+                            IF => {
+                                let start = self.start;
+                                let stmt = self.parse_stmt();
+                                Some(Block {
+                                    stmts: vec![stmt],
+                                    spans: vec![(start as u16, self.start as u16)],
+                                })
+                            }
+                            _ => Some(self.parse_block()),
+                        }
+                    }
+                    _ => None,
+                };
 
                 match pat {
-                    Some(pat) => Stmt::IfLet(pat, cond, body),
-                    None => Stmt::If(cond, body),
+                    Some(pat) => Stmt::IfLet(pat, cond, body, else_body),
+                    None => Stmt::If(cond, body, else_body),
+                }
+            }
+            MATCH => {
+                self.next();
+                let e = self.parse_expr();
+                self.parse(LBRACE);
+                let mut arms = vec![];
+                while self.token != RBRACE {
+                    let pat = self.parse_pattern();
+                    self.parse(FATARROW);
+                    let body = self.parse_block();
+                    arms.push((pat, body));
+                    if self.token == COMMA {
+                        self.next();
+                    }
                 }
+                self.parse(RBRACE);
+                Stmt::Match(e, arms)
             }
             LET => {
                 self.next();
@@ -691,8 +1214,28 @@ impl<'a> Parser<'a> {
                 Stmt::Return(e)
             }
             _ => {
+                let start = self.start;
                 let e = self.parse_expr();
                 let stmt = match self.token {
+                    // outer: while ... { }
+                    COLON => {
+                        let name = match e.kind {
+                            ExprKind::Name(name) => name,
+                            _ => {
+                                error_at(start, self.end, "expected loop label");
+                            }
+                        };
+                        self.next();
+                        match self.parse_stmt() {
+                            Stmt::For(None, init, cond, post, body) => {
+                                Stmt::For(Some(name), init, cond, post, body)
+                            }
+                            Stmt::While(None, cond, body) => Stmt::While(Some(name), cond, body),
+                            _ => {
+                                error_at(start, self.start, "only loops can be labeled");
+                            }
+                        }
+                    }
                     ASSIGN => {
                         self.next();
                         let x = self.parse_expr();
@@ -721,15 +1264,17 @@ impl<'a> Parser<'a> {
         fn precedence(op: Token) -> i32 {
             match op {
                 AND => 0,
+                OROR => 1,
+                ANDAND => 2,
                 LT | GT | LE | GE | EQ | NE => 10,
-                AMPERSAND | LSHIFT | RSHIFT => 15,
+                AMPERSAND | PIPE | CARET | LSHIFT | RSHIFT => 15,
                 PLUS | MINUS => 20,
-                STAR | SLASH => 30,
+                STAR | SLASH | PERCENT => 30,
                 _ => -1,
             }
         }
 
-        let start = self.start;
+        let start = lhs.span.0;
         while precedence(self.token) >= min_precedence {
             let op = self.token;
             let i = precedence(op);
@@ -742,7 +1287,7 @@ impl<'a> Parser<'a> {
 
             lhs = Expr {
                 kind: ExprKind::Binary(op, lhs.into(), rhs.into()),
-                span: (start as u16, self.end as u16),
+                span: (start, self.end as u16),
             };
         }
 
@@ -750,8 +1295,20 @@ impl<'a> Parser<'a> {
     }
 
     fn parse_expr(&mut self) -> Expr {
+        let start = self.start;
         let lhs = self.parse_as();
-        self.parse_binary(lhs, 0)
+        let cond = self.parse_binary(lhs, 0);
+        if self.token != QUESTION {
+            return cond;
+        }
+        self.next();
+        let x = self.parse_expr();
+        self.parse(COLON);
+        let y = self.parse_expr();
+        Expr {
+            kind: ExprKind::Cond(cond.into(), x.into(), y.into()),
+            span: (start as u16, self.end as u16),
+        }
     }
 
     fn parse_as(&mut self) -> Expr {
@@ -771,7 +1328,16 @@ impl<'a> Parser<'a> {
     fn parse_unary(&mut self) -> Expr {
         let start = self.start as u16;
         match self.token {
-            STAR | AMPERSAND => {
+            ANDAND => {
+                self.next();
+                let name = self.token_string();
+                self.parse(NAME);
+                Expr {
+                    kind: ExprKind::LabelAddr(name),
+                    span: (start as u16, self.end as u16),
+                }
+            }
+            STAR | AMPERSAND | MINUS | TILDE | BANG => {
                 let op = self.token;
                 self.next();
                 let e = self.parse_unary();
@@ -817,17 +1383,17 @@ impl<'a> Parser<'a> {
                     let i: u32 = match s.parse() {
                         Ok(i) => i,
                         Err(e) => {
-                            print_cursor(self.text, self.start, self.end);
-                            println!("error parsing field index: {}", e);
-                            error();
+                            error_at(
+                                self.start,
+                                self.end,
+                                format!("error parsing field index: {}", e),
+                            );
                         }
                     };
                     ExprKind::TupleField(e.into(), i)
                 }
                 _ => {
-                    print_cursor(self.text, self.start, self.end);
-                    println!("expected field name or index");
-                    error();
+                    error_at(self.start, self.end, "expected field name or index");
                 }
             };
 
@@ -877,6 +1443,11 @@ impl<'a> Parser<'a> {
                         b'n' => b'\n',
                         b't' => b'\t',
                         b'r' => b'\r',
+                        b'0' => 0,
+                        b'x' => {
+                            let hex = std::str::from_utf8(&s[3..5]).unwrap();
+                            u8::from_str_radix(hex, 16).unwrap()
+                        }
                         c => c,
                     },
                     c => c,
@@ -894,6 +1465,72 @@ impl<'a> Parser<'a> {
                 self.parse(RPARENS);
                 ExprKind::Sizeof(ty)
             }
+            ALIGNOF => {
+                self.next();
+                self.parse(LPARENS);
+                let ty = self.parse_type();
+                self.parse(RPARENS);
+                ExprKind::Alignof(ty)
+            }
+            ASM => {
+                self.next();
+                self.parse(LPARENS);
+                let template = self.token_string();
+                self.parse(STRING);
+                let mut constraints = vec![];
+                let mut operands = vec![];
+                let mut clobbers = vec![];
+                // Outputs, then inputs.
+                for &output in &[true, false] {
+                    if self.token != COLON {
+                        break;
+                    }
+                    self.next();
+                    while self.token == STRING {
+                        let constraint = self.token_string();
+                        if constraint[1..].starts_with('=') != output {
+                            let msg = match output {
+                                true => "output constraint must start with '='",
+                                false => "input constraint can't start with '='",
+                            };
+                            error_at(self.start, self.end, msg);
+                        }
+                        // LLVM has no read-write operands, only tied ones.
+                        if constraint.contains('+') {
+                            let msg = "use an '=' output and a tied input instead of '+'";
+                            error_at(self.start, self.end, msg);
+                        }
+                        self.next();
+                        self.parse(LPARENS);
+                        let e = self.parse_expr();
+                        self.parse(RPARENS);
+                        constraints.push(constraint);
+                        operands.push(e);
+                        if self.token != COMMA {
+                            break;
+                        }
+                        self.next();
+                    }
+                }
+                if self.token == COLON {
+                    self.next();
+                    while self.token == STRING {
+                        clobbers.push(self.token_string());
+                        self.next();
+                        if self.token != COMMA {
+                            break;
+                        }
+                        self.next();
+                    }
+                }
+                self.parse(RPARENS);
+                ExprKind::InlineAsm {
+                    template: template,
+                    constraints: constraints,
+                    operands: operands,
+                    clobbers: clobbers,
+                }
+            }
             LBRACE => {
                 self.next();
                 let mut fields = vec![];
@@ -915,16 +1552,25 @@ impl<'a> Parser<'a> {
             LBRACKET => {
                 self.next();
                 let mut elems = vec![];
+                let mut count = None;
                 while self.token != RBRACKET {
                     let e = self.parse_expr();
                     elems.push(e);
+                    if elems.len() == 1 && self.token == SEMICOLON {
+                        self.next();
+                        count = Some(self.parse_expr());
+                        break;
+                    }
                     if self.token != COMMA {
                         break;
                     }
                     self.next();
                 }
                 self.parse(RBRACKET);
-                ExprKind::Array(elems)
+                match count {
+                    Some(n) => ExprKind::ArrayRepeat(elems.pop().unwrap().into(), n.into()),
+                    None => ExprKind::Array(elems),
+                }
             }
             LPARENS => {
                 self.next();
@@ -974,9 +1620,7 @@ impl<'a> Parser<'a> {
                 ExprKind::Name(name)
             }
             _ => {
-                print_cursor(self.text, self.start, self.end);
-                println!("expected expression");
-                error();
+                error_at(self.start, self.end, "expected expression");
             }
         };
         let span = (start as u16, self.end as u16);
@@ -1017,6 +1661,7 @@ impl<'a> Parser<'a> {
                     params,
                     ret,
                     var_args,
+                    call_conv: CallConv::C,
                 };
                 Type::Func(ty.into())
             }
@@ -1040,22 +1685,11 @@ impl<'a> Parser<'a> {
             }
             LBRACKET => {
                 self.next();
-                let s = self.token_string();
-                let i = self.start;
-                let j = self.end;
-                self.parse(INTEGER);
-                let n: u32 = match s.parse() {
-                    Ok(n) => n,
-                    Err(e) => {
-                        print_cursor(self.text, i, j);
-                        println!("unable to parse array element count: {}", e);
-                        error();
-                    }
-                };
+                let n = self.parse_expr();
                 self.parse(RBRACKET);
                 let elem_ty = self.parse_type();
 
-                Type::Array(n, elem_ty.into())
+                Type::Array(n.into(), elem_ty.into())
             }
             NAME => {
                 let name = self.token_string();
@@ -1068,9 +1702,7 @@ impl<'a> Parser<'a> {
                 Type::Pointer(ty.into())
             }
             _ => {
-                print_cursor(self.text, self.start, self.end);
-                println!("expected type");
-                error();
+                error_at(self.start, self.end, "expected type");
             }
         }
     }
@@ -1084,8 +1716,10 @@ impl<'a> Parser<'a> {
             self.next();
             return;
         }
-        print_cursor(self.text, self.start, self.end);
-        println!("expected {:?}, got {:?}", token, self.token);
-        error();
+        error_at(
+            self.start,
+            self.end,
+            format!("expected {:?}, got {:?}", token, self.token),
+        );
     }
 }
@@ -0,0 +1,45 @@
+use mylang::llvm;
+
+/// A function that fails to build becomes a declaration, and the functions
+/// after it are still built correctly.
+#[test]
+fn codegen_errors() {
+    let text = "
+fn a() -> i32 {
+    outer: while true {
+        break inner;
+    }
+    return 0;
+}
+
+fn b() -> i32 {
+    let n = 0;
+    while n < 5 {
+        n += 1;
+        if n == 3 {
+            break;
+        }
+    }
+    return n;
+}
+
+fn c() {
+    continue;
+}
+";
+    let module = mylang::lower(text).unwrap();
+    unsafe {
+        let mut options = llvm::Options::default();
+        options.debug_info = true;
+        let (_machine, llmodule, errors) = llvm::build_partial(&module, "codegen_errors", &options);
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].message.contains("inner"), "{}", errors[0].message);
+        assert!(
+            errors[1].message.contains("continue"),
+            "{}",
+            errors[1].message
+        );
+        assert_eq!(llvm::verify_report(llmodule.0), Ok(()));
+        assert_eq!(llvm::jit_run(llmodule, "b"), 3);
+    }
+}
@@ -0,0 +1,22 @@
+use mylang::llvm;
+use std::env;
+use std::fs;
+
+#[test]
+fn compile() {
+    let out_path = env::temp_dir().join("mylang_compile_test.o");
+    let out_path = out_path.to_str().unwrap();
+    let text = "fn main() -> i32 {\n    return 0;\n}\n";
+    let options = llvm::Options::default();
+    mylang::compile(text, "compile.mylang", &options, out_path).unwrap();
+    let object = fs::read(out_path).unwrap();
+    fs::remove_file(out_path).unwrap();
+    assert_eq!(&object[..4], b"\x7fELF");
+
+    // Errors are returned, pointing into the source.
+    let text = "fn main() -> i32 {\n    return 1.0 as bool;\n}\n";
+    let e = mylang::compile(text, "compile.mylang", &options, out_path).unwrap_err();
+    assert_eq!(e.message, "cannot cast F32 to Bool");
+    let (start, _) = e.span.unwrap();
+    assert!(text[start..].starts_with("1.0 as bool"));
+}
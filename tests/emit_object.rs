@@ -0,0 +1,13 @@
+use mylang::llvm;
+
+#[test]
+fn emit_object_to_memory() {
+    let text = "fn main() -> i32 {\n    return 0;\n}\n";
+    let module = mylang::lower(text).unwrap();
+    unsafe {
+        let options = llvm::Options::default();
+        let (machine, llmodule) = llvm::build(&module, "emit_object.mylang", &options);
+        let object = llvm::emit_object_to_memory(machine.0, llmodule.0);
+        assert_eq!(&object[..4], b"\x7fELF");
+    }
+}
@@ -0,0 +1,121 @@
+/// Lowers `text`, which must fail, returning the message and the source
+/// text from the start of the error's span.
+fn lower_error(text: &str) -> (String, &str) {
+    let e = match mylang::lower(text) {
+        Ok(_) => panic!("expected an error"),
+        Err(e) => e,
+    };
+    let (start, _) = e.span.expect("error has no span");
+    (e.message, &text[start..])
+}
+
+#[test]
+fn match_errors() {
+    let text = "
+type option enum { Some(i32), None() }
+
+fn f(x: option) -> i32 {
+    match x {
+        Some(y) => { return y; }
+        None() => { return 0; }
+        Some(z) => { return z; }
+    }
+    return 0;
+}
+";
+    let (message, at) = lower_error(text);
+    assert_eq!(message, "variant \"Some\" is matched twice");
+    assert!(at.starts_with("Some(z)"), "{}", at);
+
+    let text = "
+type option enum { Some(i32), None() }
+
+fn f(x: option) -> i32 {
+    match x {
+        Some(y) => { return y; }
+        a => { return 0; }
+        b => { return 1; }
+    }
+    return 0;
+}
+";
+    let (message, at) = lower_error(text);
+    assert_eq!(message, "match has more than one default arm");
+    assert!(at.starts_with("b =>"), "{}", at);
+}
+
+#[test]
+fn enum_discriminant_errors() {
+    let text = "
+type flags enum {
+    A = 0x10,
+    B = 0b1_0000,
+}
+";
+    let (message, at) = lower_error(text);
+    assert_eq!(message, "\"A\" and \"B\" have the same discriminant 16");
+    assert!(at.starts_with("B = 0b1_0000"), "{}", at);
+
+    let text = "
+type big enum {
+    Max = 0xffff_ffff,
+    Next,
+}
+";
+    let (message, at) = lower_error(text);
+    assert_eq!(message, "discriminant of \"Next\" overflows u32");
+    assert!(at.starts_with("Next,"), "{}", at);
+}
+
+#[test]
+fn const_expr_errors() {
+    let text = "
+const big: i32 = 2147483647 + 1;
+";
+    let (message, at) = lower_error(text);
+    assert_eq!(message, "constant expression overflows or is undefined");
+    assert!(at.starts_with("2147483647 + 1"), "{}", at);
+
+    let text = "
+fn f() {
+    let x: [2 - 3]i32;
+}
+";
+    let (message, at) = lower_error(text);
+    assert_eq!(message, "constant expression overflows or is undefined");
+    assert!(at.starts_with("2 - 3"), "{}", at);
+
+    let text = "
+fn f() {
+    let x: [true]i32;
+}
+";
+    let (message, at) = lower_error(text);
+    assert_eq!(message, "array element count must be an integer");
+    assert!(at.starts_with("true"), "{}", at);
+}
+
+#[test]
+fn literal_errors() {
+    let (message, at) = lower_error("const x: i32 = 0x1g;");
+    assert_eq!(message, "invalid base 16 integer literal");
+    assert!(at.starts_with("0x1g;"), "{}", at);
+
+    let (message, at) = lower_error("const x: f32 = 1_.5;");
+    assert_eq!(message, "`_` in a numeric literal must be between digits");
+    assert!(at.starts_with("1_.5;"), "{}", at);
+}
+
+#[test]
+fn asm_errors() {
+    let text = "
+fn f() -> i32 {
+    let x = 1;
+    asm(\"incl $0\" : : \"+r\"(x));
+    return x;
+}
+";
+    let (message, at) = lower_error(text);
+    assert_eq!(message, "use an '=' output and a tied input instead of '+'");
+    assert!(at.starts_with("\"+r\""), "{}", at);
+}
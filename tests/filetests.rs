@@ -68,9 +68,10 @@ impl<T, E: fmt::Display> Context for Result<T, E> {
 
 fn run_test(compiler: &Path, file: &Path) -> Result<(), String> {
     let mut compiler = Command::new(compiler);
+    compiler.args(file_args(file));
     compiler.arg(file);
-    let output = run_command(&mut compiler)?;
-    println!("{:?} {}", compiler, output);
+    let compiler_output = run_command(&mut compiler)?;
+    println!("{:?} {}", compiler, compiler_output);
 
     if !Path::new("./a.out").exists() {
         return Err("a.out doesn't exist".into());
@@ -80,6 +81,8 @@ fn run_test(compiler: &Path, file: &Path) -> Result<(), String> {
     fs::remove_file("./a.out").ctx("removing a.out")?;
     println!("{:?}\n{}", a_out, output);
 
+    // Check the compiler's output (e.g. --print-llvm) before the program's.
+    let output = format!("{}{}", compiler_output, output);
     let checker = file_checker(file);
     let success = checker.check(&output, &()).ctx("filecheck")?;
     if !success {
@@ -108,6 +111,22 @@ fn run_command(command: &mut Command) -> Result<String, String> {
     Ok(s)
 }
 
+/// `// args: ...` comments in the file are passed to the compiler.
+fn file_args(file: &Path) -> Vec<String> {
+    let file = fs::read_to_string(file).unwrap();
+    let mut args = vec![];
+    for line in file.lines() {
+        let line = line.trim_start();
+        if !line.starts_with("// args: ") {
+            continue;
+        }
+        for arg in line[9..].split_whitespace() {
+            args.push(arg.to_string());
+        }
+    }
+    args
+}
+
 /// Comments in the file are parsed as checker directives.
 fn file_checker(file: &Path) -> Checker {
     let file = fs::read_to_string(file).unwrap();
@@ -0,0 +1,45 @@
+use mylang::ir::{Binop, Module2Builder, Type};
+use mylang::llvm;
+
+/// Builds a module with a struct, a func taking it, and a main that calls
+/// it, then runs main.
+#[test]
+fn module_builder() {
+    let mut b = Module2Builder::new();
+    let i32_ty = b.add_type(Type::I32);
+    let point = b.add_struct("point", &[("x", i32_ty), ("y", i32_ty)]);
+    let sum = b.add_func("sum", &[("p", point)], i32_ty, false);
+    let main = b.add_func("main", &[], i32_ty, false);
+
+    b.begin_body(sum);
+    let p = b.param(0);
+    let x = b.field(p.clone(), 0);
+    let y = b.field(p, 1);
+    let x_plus_y = b.binary(Binop::Add, x, y);
+    b.emit_return(x_plus_y);
+    b.end_body();
+
+    b.begin_body(main);
+    let p = b.add_local(point);
+    let p = b.local(p);
+    let x = b.field(p.clone(), 0);
+    let three = b.int(i32_ty, 3);
+    b.emit_assign(x, three);
+    let y = b.field(p.clone(), 1);
+    let four = b.int(i32_ty, 4);
+    b.emit_assign(y, four);
+    let call = b.call(sum, vec![p]);
+    b.emit_return(call);
+    b.end_body();
+
+    let module = b.finish();
+    assert_eq!(module.func_bodys.len(), 2);
+    unsafe {
+        let options = llvm::Options::default();
+        let (_machine, llmodule) = llvm::build(&module, "module_builder", &options);
+        let ir = llvm::ir_string(llmodule.0);
+        assert!(ir.contains("%point = type { i32, i32 }"), "{}", ir);
+        assert_eq!(llvm::verify_report(llmodule.0), Ok(()));
+        assert_eq!(llvm::jit_run(llmodule, "main"), 7);
+    }
+}
@@ -0,0 +1,39 @@
+use llvm_sys::*;
+use mylang::llvm;
+
+/// Two functions with different problems are reported separately, each
+/// with its own name.
+#[test]
+fn verify_errors() {
+    unsafe {
+        let llmodule = llvm::Module(LLVMModuleCreateWithName(b"verify\0".as_ptr() as *const i8));
+        let i32_ty = LLVMInt32Type();
+        let fnty = LLVMFunctionType(i32_ty, std::ptr::null_mut(), 0, 0);
+        let b = LLVMCreateBuilder();
+
+        // No terminator.
+        let f = LLVMAddFunction(llmodule.0, b"f\0".as_ptr() as *const i8, fnty);
+        let entry = LLVMAppendBasicBlock(f, b"entry\0".as_ptr() as *const i8);
+        LLVMPositionBuilderAtEnd(b, entry);
+        LLVMBuildAdd(
+            b,
+            LLVMConstInt(i32_ty, 1, 0),
+            LLVMConstInt(i32_ty, 2, 0),
+            b"\0".as_ptr() as *const i8,
+        );
+
+        // Returns void from a function returning i32.
+        let g = LLVMAddFunction(llmodule.0, b"g\0".as_ptr() as *const i8, fnty);
+        let entry = LLVMAppendBasicBlock(g, b"entry\0".as_ptr() as *const i8);
+        LLVMPositionBuilderAtEnd(b, entry);
+        LLVMBuildRetVoid(b);
+        LLVMDisposeBuilder(b);
+
+        let errors = llvm::verify_errors(llmodule.0);
+        assert_eq!(errors.len(), 2, "{:?}", errors);
+        assert_eq!(errors[0].function.as_deref(), Some("f"));
+        assert!(errors[0].message.contains("does not have terminator"));
+        assert_eq!(errors[1].function.as_deref(), Some("g"));
+        assert!(errors[1].message.contains("return type does not match"));
+    }
+}